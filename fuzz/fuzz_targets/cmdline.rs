@@ -0,0 +1,27 @@
+//! Fuzz the kernel command line parser with arbitrary UTF-8
+//!
+//! virtintrd is a binary crate, so the parser module is built from its
+//! source here. Run with `cargo fuzz run cmdline`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code)]
+#[path = "../../src/cmdline.rs"]
+mod cmdline;
+
+use cmdline::Cmdline;
+
+fuzz_target!(|data: &str| {
+    let cmdline = Cmdline::parse(data);
+    for (key, _) in cmdline.params() {
+        let _ = cmdline.get(key);
+        let _ = cmdline.get_all(key).count();
+    }
+    for (name, _) in cmdline.options() {
+        let _ = cmdline.get_option(name);
+    }
+    let _ = cmdline.deprecated_params().count();
+    let _ = cmdline.init_args();
+});
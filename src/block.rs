@@ -0,0 +1,157 @@
+//! Block device discovery and filesystem superblock probing
+//!
+//! This is just enough of blkid to find the root filesystem by LABEL= or
+//! UUID= and to know what filesystem type to mount it as.
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+/// What we found in a filesystem superblock
+#[derive(Debug)]
+pub struct Superblock {
+    pub fstype: &'static str,
+    pub uuid: Option<String>,
+    pub label: Option<String>,
+}
+
+fn read_at(file: &File, offset: u64, len: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut buf = vec![0u8; len];
+    match file.read_exact_at(&mut buf, offset) {
+        Ok(()) => Ok(Some(buf)),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn le16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn le32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+/// Format a 16 byte uuid the usual way, or None if it is all zeros
+fn format_uuid(bytes: &[u8]) -> Option<String> {
+    if bytes.iter().all(|b| *b == 0) {
+        return None;
+    }
+    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    Some(format!(
+        "{}-{}-{}-{}-{}",
+        hex[0..4].concat(),
+        hex[4..6].concat(),
+        hex[6..8].concat(),
+        hex[8..10].concat(),
+        hex[10..16].concat()
+    ))
+}
+
+/// Extract a nul-padded label, or None if it is empty
+fn format_label(bytes: &[u8]) -> Option<String> {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    let label = String::from_utf8_lossy(&bytes[..end]).trim().to_string();
+    if label.is_empty() {
+        None
+    } else {
+        Some(label)
+    }
+}
+
+/// Detect the filesystem on a block device or image file
+pub fn probe(path: &Path) -> io::Result<Option<Superblock>> {
+    let file = File::open(path)?;
+
+    if let Some(buf) = read_at(&file, 0, 4096)? {
+        if &buf[0..4] == b"XFSB" {
+            return Ok(Some(Superblock {
+                fstype: "xfs",
+                uuid: format_uuid(&buf[32..48]),
+                label: format_label(&buf[108..120]),
+            }));
+        }
+        if &buf[0..4] == b"hsqs" {
+            return Ok(Some(Superblock {
+                fstype: "squashfs",
+                uuid: None,
+                label: None,
+            }));
+        }
+    }
+
+    // ext2/3/4 and erofs both have their superblock at 1024
+    if let Some(buf) = read_at(&file, 1024, 1024)? {
+        if le16(&buf, 56) == 0xef53 {
+            return Ok(Some(Superblock {
+                fstype: "ext4",
+                uuid: format_uuid(&buf[104..120]),
+                label: format_label(&buf[120..136]),
+            }));
+        }
+        if le32(&buf, 0) == 0xe0f5e1e2 {
+            return Ok(Some(Superblock {
+                fstype: "erofs",
+                uuid: format_uuid(&buf[48..64]),
+                label: format_label(&buf[64..80]),
+            }));
+        }
+    }
+
+    if let Some(buf) = read_at(&file, 65536, 4096)? {
+        if &buf[64..72] == b"_BHRfS_M" {
+            return Ok(Some(Superblock {
+                fstype: "btrfs",
+                uuid: format_uuid(&buf[32..48]),
+                label: format_label(&buf[299..555]),
+            }));
+        }
+    }
+
+    Ok(None)
+}
+
+/// List the device nodes of all block devices the kernel knows about
+fn block_devices() -> io::Result<Vec<PathBuf>> {
+    let mut devices = Vec::new();
+    for entry in fs::read_dir("/sys/class/block")? {
+        let entry = entry?;
+        devices.push(Path::new("/dev").join(entry.file_name()));
+    }
+    devices.sort();
+    Ok(devices)
+}
+
+/// Check if a superblock matches a LABEL=label or UUID=uuid specification
+fn matches(sb: &Superblock, spec: &str) -> bool {
+    if let Some(label) = spec.strip_prefix("LABEL=") {
+        sb.label.as_deref() == Some(label)
+    } else if let Some(uuid) = spec.strip_prefix("UUID=") {
+        sb.uuid
+            .as_deref()
+            .is_some_and(|u| u.eq_ignore_ascii_case(uuid))
+    } else {
+        false
+    }
+}
+
+/// Find the block device for a root= style device specification: a device
+/// path, LABEL=label or UUID=uuid. Returns None if it doesn't exist (yet).
+pub fn find_device(spec: &str) -> io::Result<Option<PathBuf>> {
+    if !spec.starts_with("LABEL=") && !spec.starts_with("UUID=") {
+        let path = Path::new(spec);
+        return Ok(path.exists().then(|| path.to_path_buf()));
+    }
+
+    for device in block_devices()? {
+        // Devices can be unreadable, e.g. empty cdrom drives, just skip those
+        if let Ok(Some(sb)) = probe(&device) {
+            if matches(&sb, spec) {
+                return Ok(Some(device));
+            }
+        }
+    }
+    Ok(None)
+}
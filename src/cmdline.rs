@@ -0,0 +1,329 @@
+//! Kernel command line parsing
+//!
+//! This follows the rules the kernel itself uses in `next_arg()` and
+//! `parse_args()`, so that we see the same parameters as the kernel does,
+//! including double-quoted values containing spaces. The one difference is
+//! that the command line is UTF-8 here, so the Latin-1 non-breaking space the
+//! kernel's `isspace()` also matches doesn't split parameters.
+
+use std::fs;
+use std::io;
+
+/// Prefix of virtintrd's own parameters, so they don't clash with the kernel's
+pub const PREFIX: &str = "virtintrd.";
+
+/// Parameters that used to be accepted without PREFIX, and still are
+pub const DEPRECATED_ALIASES: &[&str] =
+    &["debug", "init", "rootfs", "mount", "mount-ro", "supervise"];
+
+#[derive(Debug, Default)]
+pub struct Cmdline {
+    params: Vec<(String, Option<String>)>,
+    init_args: Vec<String>,
+}
+
+/// The kernel's `isspace()` for ASCII, which unlike `is_ascii_whitespace()`
+/// includes '\v'
+fn is_space(c: u8) -> bool {
+    c.is_ascii_whitespace() || c == b'\x0b'
+}
+
+/// Skip leading whitespace
+fn skip_spaces(args: &str) -> &str {
+    args.trim_start_matches(|c: char| c.is_ascii() && is_space(c as u8))
+}
+
+/// Split off the next parameter, returning the parameter name, its value
+/// (if there was a '=') and the remaining string.
+///
+/// Whitespace inside double quotes doesn't split parameters. A quote at the
+/// start of the parameter or of the value, and the matching one at the end,
+/// are removed. Quotes elsewhere are kept as-is, just like the kernel does.
+/// A '=' right at the start doesn't count, so "=x" is a parameter without a
+/// value.
+fn next_arg(args: &str) -> (&str, Option<&str>, &str) {
+    let bytes = args.as_bytes();
+    let mut start = 0;
+    let mut in_quote = false;
+    let mut quoted = false;
+
+    if bytes.first() == Some(&b'"') {
+        start = 1;
+        in_quote = true;
+        quoted = true;
+    }
+
+    let mut equals = None;
+    let mut end = start;
+    while end < bytes.len() {
+        let c = bytes[end];
+        if is_space(c) && !in_quote {
+            break;
+        }
+        if equals.is_none() && c == b'=' && end > start {
+            equals = Some(end);
+        }
+        if c == b'"' {
+            in_quote = !in_quote;
+        }
+        end += 1;
+    }
+
+    let ends_with_quote = end > start && bytes[end - 1] == b'"';
+    let (param, value) = match equals {
+        None => {
+            let param_end = if quoted && ends_with_quote {
+                end - 1
+            } else {
+                end
+            };
+            (&args[start..param_end], None)
+        }
+        Some(equals) => {
+            let mut value_start = equals + 1;
+            let value_quoted = bytes.get(value_start) == Some(&b'"');
+            if value_quoted {
+                value_start += 1;
+            }
+            let value_end = if (quoted || value_quoted) && ends_with_quote {
+                end - 1
+            } else {
+                end
+            };
+            let value = &args[value_start.min(value_end)..value_end];
+            (&args[start..equals], Some(value))
+        }
+    };
+
+    (param, value, skip_spaces(&args[end..]))
+}
+
+impl Cmdline {
+    /// Parse a kernel command line
+    pub fn parse(cmdline: &str) -> Self {
+        let mut result = Cmdline::default();
+        let mut rest = skip_spaces(cmdline);
+
+        while !rest.is_empty() {
+            let (param, value, next) = next_arg(rest);
+            rest = next;
+
+            if param == "--" && value.is_none() {
+                // Everything after "--" is for init, and the kernel parses
+                // the arguments with the same rules
+                while !rest.is_empty() {
+                    let (arg, value, next) = next_arg(rest);
+                    rest = next;
+                    result.init_args.push(match value {
+                        Some(value) => format!("{}={}", arg, value),
+                        None => arg.to_string(),
+                    });
+                }
+                break;
+            }
+
+            result
+                .params
+                .push((param.to_string(), value.map(str::to_string)));
+        }
+
+        result
+    }
+
+    /// Read and parse /proc/cmdline
+    pub fn read() -> io::Result<Self> {
+        fs::read_to_string("/proc/cmdline").map(|s| Self::parse(&s))
+    }
+
+    /// Iterate over all parameters in order, as (name, value) pairs
+    pub fn params(&self) -> impl Iterator<Item = (&str, Option<&str>)> {
+        self.params
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_deref()))
+    }
+
+    /// Get the value of key. If it was specified several times the last one
+    /// wins, and a key without a value gives an empty string.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.params()
+            .filter(|(k, _)| *k == key)
+            .last()
+            .map(|(_, value)| value.unwrap_or(""))
+    }
+
+    /// Get the values of all occurrences of key=value, in order
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> {
+        self.params()
+            .filter(move |(k, _)| *k == key)
+            .filter_map(|(_, value)| value)
+    }
+
+    /// Iterate over virtintrd's own parameters in order, as (name, value)
+    /// pairs with PREFIX removed from the name. This includes the
+    /// deprecated unprefixed aliases.
+    pub fn options(&self) -> impl Iterator<Item = (&str, Option<&str>)> {
+        self.params()
+            .filter_map(|(key, value)| match key.strip_prefix(PREFIX) {
+                Some(name) => Some((name, value)),
+                None if DEPRECATED_ALIASES.contains(&key) => Some((key, value)),
+                None => None,
+            })
+    }
+
+    /// Check if one of virtintrd's own parameters was given, with or
+    /// without a value
+    pub fn has_option(&self, name: &str) -> bool {
+        self.options().any(|(n, _)| n == name)
+    }
+
+    /// Like get(), for one of virtintrd's own parameters
+    pub fn get_option(&self, name: &str) -> Option<&str> {
+        self.options()
+            .filter(|(n, _)| *n == name)
+            .last()
+            .map(|(_, value)| value.unwrap_or(""))
+    }
+
+    /// Iterate over the deprecated unprefixed parameters that were used
+    pub fn deprecated_params(&self) -> impl Iterator<Item = &str> {
+        self.params()
+            .map(|(key, _)| key)
+            .filter(|key| DEPRECATED_ALIASES.contains(key))
+    }
+
+    /// The arguments after "--", which the kernel passes on to init
+    pub fn init_args(&self) -> &[String] {
+        &self.init_args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(cmdline: &str) -> Vec<(String, Option<String>)> {
+        Cmdline::parse(cmdline).params
+    }
+
+    fn param(name: &str, value: Option<&str>) -> (String, Option<String>) {
+        (name.to_string(), value.map(str::to_string))
+    }
+
+    #[test]
+    fn plain_params() {
+        assert_eq!(
+            params("  ro console=ttyS0\tquiet  "),
+            [
+                param("ro", None),
+                param("console", Some("ttyS0")),
+                param("quiet", None)
+            ]
+        );
+        assert_eq!(params(""), []);
+        assert_eq!(params("a=b=c"), [param("a", Some("b=c"))]);
+        assert_eq!(params("a="), [param("a", Some(""))]);
+        // Like all of the kernel's isspace()
+        assert_eq!(
+            params("a\x0bb\x0cc\r\nd"),
+            [
+                param("a", None),
+                param("b", None),
+                param("c", None),
+                param("d", None)
+            ]
+        );
+    }
+
+    #[test]
+    fn leading_equals() {
+        // The kernel only looks for a '=' after the first character
+        assert_eq!(params("=x a"), [param("=x", None), param("a", None)]);
+        assert_eq!(params("=x=y"), [param("=x", Some("y"))]);
+        assert_eq!(params("="), [param("=", None)]);
+        assert_eq!(params("\"=x\""), [param("=x", None)]);
+    }
+
+    #[test]
+    fn quoted_values() {
+        assert_eq!(
+            params("init.arg=\"hello world\" x"),
+            [param("init.arg", Some("hello world")), param("x", None)]
+        );
+        assert_eq!(params("a=\"\""), [param("a", Some(""))]);
+        // Quotes inside the value are kept
+        assert_eq!(params("a=b\"c d\""), [param("a", Some("b\"c d\""))]);
+    }
+
+    #[test]
+    fn quoted_params() {
+        assert_eq!(params("\"a b\""), [param("a b", None)]);
+        assert_eq!(params("\"a b=c\""), [param("a b", Some("c"))]);
+        // The kernel keeps the quote before the '=' in this case
+        assert_eq!(params("\"a b\"=c"), [param("a b\"", Some("c"))]);
+    }
+
+    #[test]
+    fn unterminated_quotes() {
+        assert_eq!(params("a=\""), [param("a", Some(""))]);
+        assert_eq!(params("\""), [param("", None)]);
+        assert_eq!(params("a=\"b c"), [param("a", Some("b c"))]);
+    }
+
+    #[test]
+    fn init_args() {
+        let cmdline = Cmdline::parse("ro -- -c \"echo hi\" x=y");
+        assert_eq!(cmdline.params, [param("ro", None)]);
+        assert_eq!(cmdline.init_args(), ["-c", "echo hi", "x=y"]);
+
+        // Only a bare "--" ends the kernel parameters
+        let cmdline = Cmdline::parse("--=x a");
+        assert_eq!(cmdline.params, [param("--", Some("x")), param("a", None)]);
+        assert!(cmdline.init_args().is_empty());
+
+        let cmdline = Cmdline::parse("a --");
+        assert_eq!(cmdline.params, [param("a", None)]);
+        assert!(cmdline.init_args().is_empty());
+    }
+
+    #[test]
+    fn last_one_wins() {
+        let cmdline = Cmdline::parse("root=/dev/vda root=/dev/vdb quiet");
+        assert_eq!(cmdline.get("root"), Some("/dev/vdb"));
+        assert_eq!(cmdline.get("quiet"), Some(""));
+        assert_eq!(cmdline.get("missing"), None);
+
+        let cmdline = Cmdline::parse("virtintrd.init=/a init=/b virtintrd.init=/c");
+        assert_eq!(cmdline.get_option("init"), Some("/c"));
+        let cmdline = Cmdline::parse("virtintrd.init=/a init=/b");
+        assert_eq!(cmdline.get_option("init"), Some("/b"));
+    }
+
+    #[test]
+    fn get_all_in_order() {
+        let cmdline = Cmdline::parse("init.arg=a x init.arg=\"b c\" init.arg init.arg=d");
+        assert_eq!(
+            cmdline.get_all("init.arg").collect::<Vec<_>>(),
+            ["a", "b c", "d"]
+        );
+    }
+
+    #[test]
+    fn options() {
+        let cmdline = Cmdline::parse("virtintrd.debug debug=1 virtintrd.mount=a mount-ro=b x.y");
+        assert!(cmdline.has_option("debug"));
+        assert!(!cmdline.has_option("supervise"));
+        assert_eq!(
+            cmdline.options().collect::<Vec<_>>(),
+            [
+                ("debug", None),
+                ("debug", Some("1")),
+                ("mount", Some("a")),
+                ("mount-ro", Some("b"))
+            ]
+        );
+        assert_eq!(
+            cmdline.deprecated_params().collect::<Vec<_>>(),
+            ["debug", "mount-ro"]
+        );
+    }
+}
@@ -0,0 +1,278 @@
+//! A minimal rescue console built into the init binary
+//!
+//! The initrd contains nothing but init, so this gives just enough commands
+//! to look around and fix things up by hand when booting fails, or when
+//! asked to stop with rd.break.
+
+use crate::cmdline::Cmdline;
+use crate::error::{Context, InitError};
+use crate::mountopts::MountOptions;
+use nix::mount::umount;
+use nix::sys::reboot::{reboot, RebootMode};
+use nix::unistd::sync;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+use std::time::Duration;
+
+type Command = fn(&[&str]) -> Result<(), InitError>;
+
+/// The console commands: (name, arguments, description, implementation)
+const COMMANDS: &[(&str, &str, &str, Command)] = &[
+    ("ls", "[path...]", "list directory contents", ls),
+    ("cat", "file...", "print files", cat),
+    (
+        "mount",
+        "[-t type] [-o options] source target",
+        "mount a filesystem, or list mounts",
+        mount,
+    ),
+    ("umount", "target", "unmount a filesystem", unmount),
+    ("dmesg", "", "print the kernel log", dmesg),
+    ("lsmod", "", "list loaded kernel modules", lsmod),
+    (
+        "insmod",
+        "file [param=value...]",
+        "load a kernel module",
+        insmod,
+    ),
+    ("tags", "", "list virtiofs tags", tags),
+    (
+        "switch-root",
+        "[newroot [init [args...]]]",
+        "switch to newroot and run init",
+        switch_root,
+    ),
+    ("reboot", "", "reboot the VM", restart),
+    ("poweroff", "", "power off the VM", poweroff),
+];
+
+fn usage(name: &str) -> InitError {
+    let args = COMMANDS
+        .iter()
+        .find(|(n, _, _, _)| *n == name)
+        .map_or("", |(_, args, _, _)| args);
+    InitError::message("usage", format!("{} {}", name, args))
+}
+
+fn ls(args: &[&str]) -> Result<(), InitError> {
+    let paths = if args.is_empty() { &["."][..] } else { args };
+    for path in paths {
+        if paths.len() > 1 {
+            println!("{}:", path);
+        }
+        let action = || format!("listing {}", path);
+        let mut entries: Vec<_> = fs::read_dir(path)
+            .context(action)?
+            .collect::<io::Result<_>>()
+            .context(action)?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let Ok(metadata) = fs::symlink_metadata(entry.path()) else {
+                continue;
+            };
+            let file_type = metadata.file_type();
+            let kind = if file_type.is_dir() {
+                'd'
+            } else if file_type.is_symlink() {
+                'l'
+            } else if file_type.is_char_device() {
+                'c'
+            } else if file_type.is_block_device() {
+                'b'
+            } else {
+                '-'
+            };
+            let mut name = entry.file_name().to_string_lossy().into_owned();
+            if let Ok(target) = fs::read_link(entry.path()) {
+                name = format!("{} -> {}", name, target.display());
+            }
+            println!(
+                "{} {:04o} {:>10} {}",
+                kind,
+                metadata.mode() & 0o7777,
+                metadata.size(),
+                name
+            );
+        }
+    }
+    Ok(())
+}
+
+fn cat(args: &[&str]) -> Result<(), InitError> {
+    if args.is_empty() {
+        return Err(usage("cat"));
+    }
+    for path in args {
+        let data = fs::read(path).context(|| format!("reading {}", path))?;
+        io::stdout()
+            .write_all(&data)
+            .context(|| "writing to the console".to_string())?;
+    }
+    Ok(())
+}
+
+fn mount(args: &[&str]) -> Result<(), InitError> {
+    if args.is_empty() {
+        return cat(&["/proc/self/mounts"]);
+    }
+
+    let mut fstype = None;
+    let mut options = "";
+    let mut positional = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match *arg {
+            "-t" => fstype = Some(*args.next().ok_or_else(|| usage("mount"))?),
+            "-o" => options = args.next().ok_or_else(|| usage("mount"))?,
+            _ => positional.push(*arg),
+        }
+    }
+    let [source, target] = positional[..] else {
+        return Err(usage("mount"));
+    };
+
+    let fstype = match fstype {
+        Some(fstype) => fstype,
+        None => crate::probe_fstype(Path::new(source), Path::new(source))?,
+    };
+    let options =
+        MountOptions::parse(fstype, options, false).context(|| format!("mounting {}", source))?;
+    if crate::TAG_FSTYPES.contains(&fstype) {
+        crate::mount_tag(fstype, source, target, &options, Duration::ZERO)
+    } else {
+        crate::mount_block(Path::new(source), target, fstype, &options)
+    }
+}
+
+fn unmount(args: &[&str]) -> Result<(), InitError> {
+    let [target] = args[..] else {
+        return Err(usage("umount"));
+    };
+    umount(target).context(|| format!("unmounting {}", target))
+}
+
+fn dmesg(_args: &[&str]) -> Result<(), InitError> {
+    let log = crate::emergency::kernel_log()
+        .ok_or_else(|| InitError::message("reading the kernel log", "not available"))?;
+    print!("{}", log);
+    Ok(())
+}
+
+fn lsmod(_args: &[&str]) -> Result<(), InitError> {
+    let modules =
+        fs::read_to_string("/proc/modules").context(|| "reading /proc/modules".to_string())?;
+    for line in modules.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if let [name, size, users, used_by, ..] = fields[..] {
+            println!(
+                "{:<24} {:>8} {} {}",
+                name,
+                size,
+                users,
+                used_by.trim_end_matches(',')
+            );
+        }
+    }
+    Ok(())
+}
+
+fn insmod(args: &[&str]) -> Result<(), InitError> {
+    let Some((path, params)) = args.split_first() else {
+        return Err(usage("insmod"));
+    };
+    crate::modules::load_module(Path::new(path), &params.join(" "))
+        .context(|| format!("loading module {}", path))
+}
+
+fn tags(_args: &[&str]) -> Result<(), InitError> {
+    let tags = crate::virtiofs_tags().ok_or_else(|| {
+        InitError::message(
+            "listing virtiofs tags",
+            "the kernel doesn't list them in /sys/fs/virtiofs",
+        )
+    })?;
+    for tag in tags {
+        println!("{}", tag);
+    }
+    Ok(())
+}
+
+fn switch_root(args: &[&str]) -> Result<(), InitError> {
+    let cmdline = Cmdline::read().unwrap_or_else(|_| Cmdline::parse(""));
+    let newroot = args.first().copied().unwrap_or("/sysroot");
+    let init_program = args
+        .get(1)
+        .copied()
+        .unwrap_or_else(|| cmdline.get_option("init").unwrap_or("/bin/sh"));
+
+    // Without arguments, init gets the ones from the command line
+    let init_args = args.get(2..).filter(|init_args| !init_args.is_empty());
+
+    let mounted = crate::mount_run_mnt(&cmdline)?;
+    match crate::finish_boot(&cmdline, newroot, init_program, init_args, mounted)? {}
+}
+
+fn restart(_args: &[&str]) -> Result<(), InitError> {
+    crate::kill_all_processes();
+    sync();
+    let errno = reboot(RebootMode::RB_AUTOBOOT).unwrap_err();
+    Err(errno).context(|| "rebooting".to_string())
+}
+
+fn poweroff(_args: &[&str]) -> Result<(), InitError> {
+    crate::power_off(&[]);
+}
+
+fn help() {
+    let builtins = [
+        ("help", "", "show this help"),
+        ("exit", "", "leave the console"),
+    ];
+    let commands = COMMANDS
+        .iter()
+        .map(|(name, args, description, _)| (*name, *args, *description));
+    for (name, args, description) in commands.chain(builtins) {
+        println!("  {:<12} {:<40} {}", name, args, description);
+    }
+}
+
+/// Run the rescue console until the user exits it, or there is no more
+/// input. exit_action describes what happens after that.
+pub fn run(exit_action: &str) {
+    println!(
+        "Entering the rescue console, type 'help' for commands or 'exit' to {}",
+        exit_action
+    );
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("virtintrd# ");
+        let _ = io::stdout().flush();
+
+        let Some(Ok(line)) = lines.next() else {
+            println!();
+            return;
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((name, args)) = words.split_first() else {
+            continue;
+        };
+
+        match *name {
+            "exit" => return,
+            "help" => help(),
+            _ => match COMMANDS.iter().find(|(n, _, _, _)| n == name) {
+                Some((_, _, _, command)) => {
+                    if let Err(err) = command(args) {
+                        eprintln!("{}", err);
+                    }
+                }
+                None => eprintln!("Unknown command '{}', try 'help'", name),
+            },
+        }
+    }
+}
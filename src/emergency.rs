@@ -0,0 +1,161 @@
+//! What to do when booting fails
+//!
+//! Instead of returning from PID 1, which panics the kernel, we print what
+//! we know about the state of the VM and then either start a rescue shell
+//! or power off, as selected with virtintrd.emergency=shell|console|poweroff.
+
+use crate::cmdline::Cmdline;
+use crate::error::InitError;
+use nix::errno::Errno;
+use nix::sys::wait::{wait, WaitStatus};
+use nix::unistd::{chdir, chroot, execve, fork, ForkResult};
+use std::fs;
+use std::path::Path;
+
+/// Exit status reported to the host when booting failed
+pub const BOOT_FAILED_STATUS: &str = "boot-failed";
+
+/// Number of kernel log lines to include in the diagnostics
+const KERNEL_LOG_LINES: usize = 30;
+
+// From linux/syslog.h
+const SYSLOG_ACTION_READ_ALL: i32 = 3;
+const SYSLOG_ACTION_SIZE_BUFFER: i32 = 10;
+
+/// Read a file from procfs. Depending on how far booting got, proc is
+/// either still at /proc or already moved to /sysroot/proc.
+fn read_proc(name: &str) -> Option<String> {
+    ["/proc", "/sysroot/proc"]
+        .iter()
+        .find_map(|proc| fs::read_to_string(Path::new(proc).join(name)).ok())
+}
+
+/// Read the kernel log buffer
+pub fn kernel_log() -> Option<String> {
+    let size = unsafe { libc::klogctl(SYSLOG_ACTION_SIZE_BUFFER, std::ptr::null_mut(), 0) };
+    if size <= 0 {
+        return None;
+    }
+    let mut buf = vec![0u8; size as usize];
+    let len = unsafe {
+        libc::klogctl(
+            SYSLOG_ACTION_READ_ALL,
+            buf.as_mut_ptr() as *mut libc::c_char,
+            size,
+        )
+    };
+    if len < 0 {
+        return None;
+    }
+    buf.truncate(len as usize);
+    Some(String::from_utf8_lossy(&buf).into_owned())
+}
+
+fn print_section(title: &str, content: Option<String>) {
+    eprintln!("--- {} ---", title);
+    match content {
+        Some(content) => eprintln!("{}", content.trim_end()),
+        None => eprintln!("(unavailable)"),
+    }
+}
+
+/// Print the state of the VM, to help figure out why booting failed
+pub fn dump_diagnostics() {
+    print_section("Kernel command line", read_proc("cmdline"));
+    print_section(
+        "Mounted filesystems",
+        read_proc("self/mounts").or_else(|| read_proc("mounts")),
+    );
+    print_section(
+        "Loaded modules",
+        read_proc("modules").map(|modules| {
+            modules
+                .lines()
+                .filter_map(|line| line.split_whitespace().next())
+                .collect::<Vec<_>>()
+                .join(" ")
+        }),
+    );
+    print_section(
+        "Virtiofs tags",
+        crate::virtiofs_tags().map(|tags| {
+            if tags.is_empty() {
+                "none".to_string()
+            } else {
+                tags.join(" ")
+            }
+        }),
+    );
+    print_section(
+        "Kernel log",
+        kernel_log().map(|log| {
+            let lines: Vec<&str> = log.lines().collect();
+            lines[lines.len().saturating_sub(KERNEL_LOG_LINES)..].join("\n")
+        }),
+    );
+}
+
+/// Find a shell to rescue with, returning the root directory it is in. The
+/// initrd is checked first, then the root filesystem if it got mounted.
+fn find_shell() -> Option<&'static str> {
+    ["/", "/sysroot"]
+        .into_iter()
+        .find(|root| Path::new(root).join("bin/sh").exists())
+}
+
+/// Run /bin/sh from the given root directory, with the console as its
+/// controlling terminal, and wait for it to exit
+fn run_shell(root: &str, cmdline: &Cmdline) {
+    eprintln!("Starting rescue shell, exit it to power off");
+    let console = crate::tty::console_device();
+    let env = match crate::init_environment(cmdline, &console) {
+        Ok(env) => env,
+        Err(e) => {
+            errorln!("Failed to set up the rescue shell: {}", e);
+            return;
+        }
+    };
+
+    match unsafe { fork() } {
+        Ok(ForkResult::Child) => {
+            crate::set_controlling_terminal(&console);
+            if root != "/" && (chroot(root).is_err() || chdir("/").is_err()) {
+                errorln!("Failed to change root to {}", root);
+                std::process::exit(127);
+            }
+            let _ = execve(c"/bin/sh", &[c"sh"], &env);
+            errorln!("Failed to execute the rescue shell");
+            std::process::exit(127);
+        }
+        Ok(ForkResult::Parent { child }) => loop {
+            match wait() {
+                Ok(WaitStatus::Exited(pid, _)) if pid == child => return,
+                Ok(WaitStatus::Signaled(pid, _, _)) if pid == child => return,
+                Ok(_) | Err(Errno::EINTR) => {}
+                Err(_) => return,
+            }
+        },
+        Err(e) => errorln!("Failed to fork rescue shell: {}", e),
+    }
+}
+
+/// Handle a failed boot: print the error and diagnostics, optionally run a
+/// rescue shell, then report the failure to the host and power off
+pub fn emergency(err: &InitError) -> ! {
+    errorln!("Boot failed while {}: {}", err.phase, err);
+    dump_diagnostics();
+
+    let cmdline = Cmdline::parse(&read_proc("cmdline").unwrap_or_default());
+    match cmdline.get_option("emergency") {
+        Some("shell") => match find_shell() {
+            Some(root) => run_shell(root, &cmdline),
+            None => crate::console::run("power off"),
+        },
+        Some("console") => crate::console::run("power off"),
+        None | Some("poweroff") => {}
+        Some(other) => warnln!("Unknown emergency action '{}', powering off", other),
+    }
+
+    crate::report_exit_status(BOOT_FAILED_STATUS);
+    crate::power_off(&[]);
+}
@@ -0,0 +1,136 @@
+//! Boot errors that record which step failed and on what
+//!
+//! Errors read like "mounting virtiofs tag 'rootfs' at /sysroot: ENOENT
+//! (tag not present)", and also carry the boot phase they happened in.
+
+use nix::errno::Errno;
+use std::fmt;
+use std::io;
+
+/// The phases of booting, in order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Setup,
+    Cmdline,
+    Devices,
+    Modules,
+    RootMount,
+    Mounts,
+    SwitchRoot,
+    Exec,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Phase::Setup => "setting up the initrd",
+            Phase::Cmdline => "parsing the kernel command line",
+            Phase::Devices => "creating device nodes",
+            Phase::Modules => "loading kernel modules",
+            Phase::RootMount => "mounting the root filesystem",
+            Phase::Mounts => "mounting additional filesystems",
+            Phase::SwitchRoot => "switching to the root filesystem",
+            Phase::Exec => "starting init",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum Cause {
+    /// A failed system call
+    Errno(Errno),
+    /// Anything else, like invalid options or timeouts
+    Message(String),
+}
+
+impl From<io::Error> for Cause {
+    fn from(err: io::Error) -> Self {
+        match err.raw_os_error() {
+            Some(errno) => Cause::Errno(Errno::from_raw(errno)),
+            None => Cause::Message(err.to_string()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InitError {
+    pub phase: Phase,
+    /// What we were doing, e.g. "mounting virtiofs tag 'rootfs' at /sysroot"
+    pub action: String,
+    pub cause: Cause,
+    /// What the errno most likely means in this context, if we know
+    pub hint: Option<String>,
+}
+
+impl InitError {
+    /// Create an error, in the Setup phase until the caller says otherwise
+    pub fn new(action: impl Into<String>, cause: Cause) -> Self {
+        InitError {
+            phase: Phase::Setup,
+            action: action.into(),
+            cause,
+            hint: None,
+        }
+    }
+
+    pub fn message(action: impl Into<String>, message: impl Into<String>) -> Self {
+        Self::new(action, Cause::Message(message.into()))
+    }
+
+    pub fn with_hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+}
+
+impl fmt::Display for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.cause {
+            Cause::Errno(errno) => write!(
+                f,
+                "{}: {:?} ({})",
+                self.action,
+                errno,
+                self.hint.as_deref().unwrap_or(errno.desc())
+            ),
+            Cause::Message(message) => write!(f, "{}: {}", self.action, message),
+        }
+    }
+}
+
+impl std::error::Error for InitError {}
+
+/// Turn errors from system calls and helpers into an InitError, describing
+/// what was being done
+pub trait Context<T> {
+    fn context<F: FnOnce() -> String>(self, action: F) -> Result<T, InitError>;
+}
+
+impl<T> Context<T> for nix::Result<T> {
+    fn context<F: FnOnce() -> String>(self, action: F) -> Result<T, InitError> {
+        self.map_err(|errno| InitError::new(action(), Cause::Errno(errno)))
+    }
+}
+
+impl<T> Context<T> for io::Result<T> {
+    fn context<F: FnOnce() -> String>(self, action: F) -> Result<T, InitError> {
+        self.map_err(|err| InitError::new(action(), err.into()))
+    }
+}
+
+impl<T> Context<T> for Result<T, String> {
+    fn context<F: FnOnce() -> String>(self, action: F) -> Result<T, InitError> {
+        self.map_err(|message| InitError::message(action(), message))
+    }
+}
+
+/// Record the boot phase an error happened in
+pub trait InPhase<T> {
+    fn phase(self, phase: Phase) -> Result<T, InitError>;
+}
+
+impl<T> InPhase<T> for Result<T, InitError> {
+    fn phase(self, phase: Phase) -> Result<T, InitError> {
+        self.map_err(|err| InitError { phase, ..err })
+    }
+}
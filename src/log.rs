@@ -0,0 +1,126 @@
+//! Logging to the kernel log
+//!
+//! Messages are written as records to /dev/kmsg, so they are interleaved with
+//! the kernel messages and shown on the console like them. Until devtmpfs is
+//! mounted, or if /dev/kmsg can't be opened, they go to stderr instead.
+//! With virtintrd.debug, debug messages are also written to stderr, the
+//! console, as the kernel doesn't show debug records there by default.
+
+use crate::cmdline::Cmdline;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Mutex;
+
+/// Message levels, with the values used by the kernel
+#[derive(Debug, Clone, Copy)]
+pub enum Level {
+    Err = 3,
+    Warning = 4,
+    Debug = 7,
+}
+
+/// The default log level, like the kernel's: everything but debug messages
+const DEFAULT_LOGLEVEL: u8 = 7;
+/// The log level with quiet, only errors
+const QUIET_LOGLEVEL: u8 = 4;
+/// The log level with virtintrd.debug, everything
+const DEBUG_LOGLEVEL: u8 = 8;
+
+/// Only messages with a level below this are shown, like for loglevel=.
+/// The kernel applies its own to the records, this is for writing to stderr,
+/// and for whether there are debug records at all.
+static LOGLEVEL: AtomicU8 = AtomicU8::new(DEFAULT_LOGLEVEL);
+
+/// Whether debug messages have to be written to the console by us
+static CONSOLE_DEBUG: AtomicBool = AtomicBool::new(false);
+
+static KMSG: Mutex<Option<File>> = Mutex::new(None);
+
+/// Set the log level from the kernel command line: loglevel=N or quiet
+/// like for the kernel, or virtintrd.debug to log everything. Debugging
+/// also stops the kernel from rate limiting writes to /dev/kmsg, unless
+/// printk.devkmsg= is set. The console log level is left alone, so the
+/// kernel's own debug messages don't flood the console.
+pub fn init(cmdline: &Cmdline) {
+    let debug = cmdline.has_option("debug");
+    let loglevel = if debug {
+        DEBUG_LOGLEVEL
+    } else if let Some(level) = cmdline.get("loglevel").and_then(|l| l.parse().ok()) {
+        level
+    } else if cmdline.get("quiet").is_some() {
+        QUIET_LOGLEVEL
+    } else {
+        DEFAULT_LOGLEVEL
+    };
+    LOGLEVEL.store(loglevel, Ordering::Relaxed);
+
+    if debug {
+        let _ = fs::write("/proc/sys/kernel/printk_devkmsg", "on\n");
+        CONSOLE_DEBUG.store(
+            cmdline.get("ignore_loglevel").is_none() && console_loglevel() <= Level::Debug as u8,
+            Ordering::Relaxed,
+        );
+    }
+}
+
+/// The kernel's current console log level, the first field of printk
+fn console_loglevel() -> u8 {
+    fs::read_to_string("/proc/sys/kernel/printk")
+        .ok()
+        .and_then(|printk| printk.split_whitespace().next()?.parse().ok())
+        .unwrap_or(DEFAULT_LOGLEVEL)
+}
+
+/// Whether messages of a level are shown on the console
+pub fn enabled(level: Level) -> bool {
+    (level as u8) < LOGLEVEL.load(Ordering::Relaxed)
+}
+
+/// Log a message, one kernel log record per line. Errors and warnings are
+/// always logged, and the kernel decides whether to show them on the
+/// console. Debug messages are only logged with virtintrd.debug.
+pub fn write(level: Level, args: fmt::Arguments) {
+    if matches!(level, Level::Debug) && !enabled(level) {
+        return;
+    }
+    let message = args.to_string();
+
+    let mut kmsg = KMSG.lock().unwrap_or_else(|e| e.into_inner());
+    // Tests run on the host, whose kernel log they shouldn't write to
+    if kmsg.is_none() && !cfg!(test) {
+        *kmsg = OpenOptions::new().write(true).open("/dev/kmsg").ok();
+    }
+    match kmsg.as_mut() {
+        Some(file) => {
+            for line in message.lines() {
+                let record = format!("<{}>virtintrd: {}\n", level as u8, line);
+                let _ = file.write_all(record.as_bytes());
+            }
+            if matches!(level, Level::Debug) && CONSOLE_DEBUG.load(Ordering::Relaxed) {
+                eprintln!("virtintrd: {}", message);
+            }
+        }
+        None if enabled(level) => eprintln!("virtintrd: {}", message),
+        None => {}
+    }
+}
+
+macro_rules! errorln {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Err, format_args!($($arg)*))
+    };
+}
+
+macro_rules! warnln {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Warning, format_args!($($arg)*))
+    };
+}
+
+macro_rules! debugln {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Debug, format_args!($($arg)*))
+    };
+}
@@ -1,37 +1,53 @@
+#[macro_use]
+mod log;
+
+mod block;
+mod cmdline;
+mod console;
+mod emergency;
+mod error;
+mod loopdev;
+mod modules;
+mod mountopts;
+mod tty;
+
+use cmdline::Cmdline;
+use error::{Cause, Context, InPhase, InitError, Phase};
+use mountopts::MountOptions;
+use nix::errno::Errno;
 use nix::mount::{mount, umount, MsFlags};
+use nix::sys::reboot::{reboot, RebootMode};
+use nix::sys::signal::{kill, Signal};
 use nix::sys::stat::{makedev, mknod, Mode, SFlag};
-use nix::unistd::{chdir, chroot, execv};
+use nix::sys::wait::{wait, waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{chdir, chroot, execve, fork, sync, ForkResult, Pid};
+use std::convert::Infallible;
 use std::ffi::CString;
 use std::fs;
-use std::io;
 use std::os::unix::fs::symlink;
-use std::path::Path;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
-static DEBUG: AtomicBool = AtomicBool::new(false);
+/// Default for how long to wait for devices to show up, in seconds
+const DEFAULT_DEVICE_TIMEOUT: u64 = 10;
 
-fn set_debug(enabled: bool) {
-    DEBUG.store(enabled, Ordering::Relaxed);
-}
+/// How long the list of virtiofs tags has to stay the same before we
+/// assume all devices have been probed
+const TAG_SETTLE_TIME: Duration = Duration::from_millis(500);
 
-macro_rules! debugln {
-    ($($arg:tt)*) => {
-        if DEBUG.load(Ordering::Relaxed) {
-            println!($($arg)*);
-        }
-    };
-}
+/// Name of the virtio-serial port the host can add to receive the init exit status
+const EXIT_STATUS_PORT: &str = "virtintrd.exitcode";
 
-fn mkdir_p(path: &str) -> io::Result<()> {
-    let path = Path::new(path);
-    if !path.exists() {
-        fs::create_dir_all(path)?;
+fn mkdir_p(path: &str) -> Result<(), InitError> {
+    if !Path::new(path).exists() {
+        fs::create_dir_all(path).context(|| format!("creating directory {}", path))?;
     }
     Ok(())
 }
 
 /// Create static device nodes
-fn create_static_devices() -> Result<(), Box<dyn std::error::Error>> {
+fn create_static_devices() -> Result<(), InitError> {
     debugln!("Creating static device nodes");
 
     // Table of device nodes to create
@@ -52,7 +68,11 @@ fn create_static_devices() -> Result<(), Box<dyn std::error::Error>> {
         }
 
         let dev = makedev(*major, *minor);
-        mknod(*path, SFlag::S_IFCHR, Mode::from_bits_truncate(*mode), dev)?;
+        match mknod(*path, SFlag::S_IFCHR, Mode::from_bits_truncate(*mode), dev) {
+            // devtmpfs already has the nodes for built-in drivers
+            Ok(()) | Err(Errno::EEXIST) => {}
+            Err(e) => return Err(e).context(|| format!("creating device node {}", path)),
+        }
     }
 
     let symlinks = [
@@ -64,48 +84,206 @@ fn create_static_devices() -> Result<(), Box<dyn std::error::Error>> {
 
     for (link_path, target) in &symlinks {
         debugln!("Creating {}", link_path);
-        symlink(target, link_path)?;
+        symlink(target, link_path).context(|| format!("creating symlink {}", link_path))?;
     }
 
     Ok(())
 }
 
-fn read_cmdline() -> io::Result<String> {
-    debugln!("Creating /proc/cmdline");
-    fs::read_to_string("/proc/cmdline").map(|s| s.trim().to_string())
+/// Parse all env.NAME=value parameters from cmdline
+fn cmdline_get_env(cmdline: &Cmdline) -> Vec<(&str, &str)> {
+    cmdline
+        .params()
+        .filter_map(|(key, value)| Some((key.strip_prefix("env.")?, value?)))
+        .filter(|(name, _)| !name.is_empty())
+        .collect()
 }
 
-fn cmdline_get<'a>(cmdline: &'a str, key: &str) -> Option<&'a str> {
-    for param in cmdline.split_whitespace() {
-        if param == key {
-            return Some("");
-        } else if let Some(value) = param.strip_prefix(&format!("{}=", key)) {
-            return Some(value);
-        }
+/// Whether the root should be mounted read-only, from the standard kernel
+/// ro and rw parameters. The last one wins, and the default is read-only.
+fn cmdline_root_read_only(cmdline: &Cmdline) -> bool {
+    cmdline
+        .params()
+        .filter(|(key, value)| value.is_none() && matches!(*key, "ro" | "rw"))
+        .last()
+        .is_none_or(|(key, _)| key == "ro")
+}
+
+/// Whether to stop at the given point of booting and enter the rescue
+/// console, from rd.break=point. A plain rd.break stops before switching root.
+fn cmdline_break_at(cmdline: &Cmdline, point: &str) -> bool {
+    match cmdline.get("rd.break") {
+        Some("") => point == "pre-pivot",
+        Some(value) => value == point,
+        None => false,
+    }
+}
+
+/// Filesystem types that mount a directory shared by the host by tag
+const TAG_FSTYPES: &[&str] = &["virtiofs", "9p"];
+
+/// Where the root filesystem comes from
+enum RootSource<'a> {
+    /// A tag shared over virtiofs or 9p
+    Tag { fstype: &'a str, tag: &'a str },
+    /// A block device: a device path, LABEL=label or UUID=uuid
+    Block(&'a str),
+    /// A filesystem image file in a virtiofs tag, mounted via a loop device
+    Image { tag: &'a str, path: &'a str },
+}
+
+/// Get the root filesystem from the standard root= and rootfstype= kernel
+/// parameters. Without root= the virtiofs tag from virtintrd.rootfs is used,
+/// and like for the kernel, root=tag rootfstype=virtiofs works too.
+/// With rootfstype=9p the tag is mounted over 9p instead.
+///
+/// If virtintrd.rootimage=[tag:]path is specified that takes precedence, and
+/// the image at path in the tag (by default the virtintrd.rootfs one) is used.
+fn cmdline_get_root(cmdline: &Cmdline) -> RootSource<'_> {
+    let rootfs_tag = cmdline.get_option("rootfs").unwrap_or("rootfs");
+    if let Some(image) = cmdline.get_option("rootimage").filter(|i| !i.is_empty()) {
+        return match image.split_once(':') {
+            Some((tag, path)) => RootSource::Image { tag, path },
+            None => RootSource::Image {
+                tag: rootfs_tag,
+                path: image,
+            },
+        };
+    }
+
+    let root = cmdline.get("root").filter(|root| !root.is_empty());
+    match cmdline.get("rootfstype") {
+        Some(fstype) if TAG_FSTYPES.contains(&fstype) => RootSource::Tag {
+            fstype,
+            tag: root.unwrap_or(rootfs_tag),
+        },
+        _ => match root {
+            Some(device) => RootSource::Block(device),
+            None => RootSource::Tag {
+                fstype: "virtiofs",
+                tag: rootfs_tag,
+            },
+        },
     }
-    None
 }
 
-/// Parse all mount= and mount-ro= parameters from cmdline
-fn cmdline_get_mounts(cmdline: &str) -> Vec<(&str, bool)> {
+/// Maximum length of a virtiofs tag
+const MAX_TAG_LEN: usize = 36;
+
+/// Check that a tag is valid, and safe to use as a file name as we create
+/// mount points named after tags
+fn validate_tag(tag: &str) -> Result<(), String> {
+    if tag.is_empty() {
+        return Err("empty tag".to_string());
+    }
+    if tag.len() > MAX_TAG_LEN {
+        return Err(format!(
+            "tag '{}' is longer than {} bytes",
+            tag, MAX_TAG_LEN
+        ));
+    }
+    if tag == "." || tag == ".." {
+        return Err(format!("invalid tag '{}'", tag));
+    }
+    if let Some(c) = tag
+        .chars()
+        .find(|c| !c.is_ascii_alphanumeric() && !matches!(c, '-' | '_' | '.'))
+    {
+        return Err(format!("invalid character {:?} in tag '{}'", c, tag));
+    }
+    Ok(())
+}
+
+/// Check that a mount path is absolute, doesn't escape the new root and
+/// isn't the new root itself, which would hide it and the API filesystems
+fn validate_mount_path(path: &str) -> Result<(), String> {
+    if !path.starts_with('/') {
+        return Err(format!("mount path '{}' must be absolute", path));
+    }
+    if path.split('/').any(|component| component == "..") {
+        return Err(format!("mount path '{}' must not contain '..'", path));
+    }
+    if path
+        .split('/')
+        .all(|component| matches!(component, "" | "."))
+    {
+        return Err(format!("mount path '{}' is the root directory", path));
+    }
+    Ok(())
+}
+
+/// An additional virtiofs or 9p mount requested on the command line
+struct MountSpec<'a> {
+    fstype: &'a str,
+    tag: &'a str,
+    /// Absolute path inside the new root to mount at, instead of /run/mnt/<tag>
+    target: Option<&'a str>,
+    options: MountOptions,
+}
+
+/// Parse all virtintrd.mount= and virtintrd.mount-ro= parameters from cmdline.
+/// The values are on the form tag[:path[:options]], where path is where in
+/// the new root to mount the tag and options a comma separated list of
+/// mount options. An empty path means the default of /run/mnt/<tag>. The
+/// option type=9p mounts the tag over 9p instead of virtiofs.
+fn cmdline_get_mounts(cmdline: &Cmdline) -> Result<Vec<MountSpec<'_>>, InitError> {
     let mut mounts = Vec::new();
 
-    for param in cmdline.split_whitespace() {
-        if let Some(tag) = param.strip_prefix("mount=") {
-            if !tag.is_empty() {
-                mounts.push((tag, false));
-            }
-        } else if let Some(tag) = param.strip_prefix("mount-ro=") {
-            if !tag.is_empty() {
-                mounts.push((tag, true));
-            }
+    for (key, value) in cmdline.options() {
+        let read_only = match key {
+            "mount" => false,
+            "mount-ro" => true,
+            _ => continue,
+        };
+        let Some(value) = value.filter(|value| !value.is_empty()) else {
+            continue;
+        };
+
+        let param = format!("{}{}={}", cmdline::PREFIX, key, value);
+
+        let mut parts = value.splitn(3, ':');
+        let tag = parts.next().unwrap_or_default();
+        let target = parts.next().filter(|target| !target.is_empty());
+        let options = parts.next().unwrap_or_default();
+
+        validate_tag(tag).context(|| param.clone())?;
+        if let Some(target) = target {
+            validate_mount_path(target).context(|| param.clone())?;
+        }
+
+        let mut fstype = "virtiofs";
+        let options: Vec<&str> = options
+            .split(',')
+            .filter(|option| match option.strip_prefix("type=") {
+                Some(t) => {
+                    fstype = t;
+                    false
+                }
+                None => true,
+            })
+            .collect();
+        if !TAG_FSTYPES.contains(&fstype) {
+            return Err(InitError::message(
+                param,
+                format!("unsupported mount type '{}'", fstype),
+            ));
         }
+
+        let options =
+            MountOptions::parse(fstype, &options.join(","), read_only).context(|| param.clone())?;
+
+        mounts.push(MountSpec {
+            fstype,
+            tag,
+            target,
+            options,
+        });
     }
 
-    mounts
+    Ok(mounts)
 }
 
-fn mount_apis() -> nix::Result<()> {
+fn mount_apis() -> Result<(), InitError> {
     let mounts = [
         (
             "sysfs",
@@ -146,13 +324,14 @@ fn mount_apis() -> nix::Result<()> {
 
     for (source, target, fstype, flags, data) in &mounts {
         debugln!("Mounting {}", *target);
-        mount(Some(*source), *target, Some(*fstype), *flags, *data)?;
+        mount(Some(*source), *target, Some(*fstype), *flags, *data)
+            .context(|| format!("mounting {} at {}", fstype, target))?;
     }
 
     Ok(())
 }
 
-fn move_mount(src: &str, dest: &str) -> nix::Result<()> {
+fn move_mount(src: &str, dest: &str) -> Result<(), InitError> {
     debugln!("Moving {} to {}", src, dest);
     mount(
         Some(src),
@@ -161,98 +340,795 @@ fn move_mount(src: &str, dest: &str) -> nix::Result<()> {
         MsFlags::MS_MOVE,
         None::<&str>,
     )
+    .context(|| format!("moving mount {} to {}", src, dest))
 }
 
-fn mount_virtiofs(tag: &str, mountpoint: &str, read_only: bool) -> nix::Result<()> {
+fn mount_virtiofs(tag: &str, mountpoint: &str, options: &MountOptions) -> nix::Result<()> {
+    let data = options.data();
     debugln!(
-        "Mounting {} at {} (read_only: {})",
+        "Mounting {} at {} (flags: {:?}, data: {})",
         tag,
         mountpoint,
-        read_only
+        options.flags,
+        data.as_deref().unwrap_or("")
     );
-    let flags = if read_only {
-        MsFlags::MS_RDONLY
-    } else {
-        MsFlags::empty()
+
+    mount(
+        Some(tag),
+        mountpoint,
+        Some("virtiofs"),
+        options.flags,
+        data.as_deref(),
+    )
+}
+
+fn mount_9p(tag: &str, mountpoint: &str, options: &MountOptions) -> nix::Result<()> {
+    let mut data = String::from("trans=virtio,version=9p2000.L");
+    if let Some(extra) = options.data() {
+        data.push(',');
+        data.push_str(&extra);
+    }
+    debugln!(
+        "Mounting {} at {} over 9p (flags: {:?}, data: {})",
+        tag,
+        mountpoint,
+        options.flags,
+        data
+    );
+
+    mount(
+        Some(tag),
+        mountpoint,
+        Some("9p"),
+        options.flags,
+        Some(data.as_str()),
+    )
+}
+
+/// How long to wait for devices, from virtintrd.timeout=<seconds>
+fn cmdline_get_timeout(cmdline: &Cmdline) -> Result<Duration, InitError> {
+    let seconds = match cmdline.get_option("timeout") {
+        Some(value) => value.parse().map_err(|_| {
+            InitError::message(
+                format!("{}timeout={}", cmdline::PREFIX, value),
+                "not a number",
+            )
+        })?,
+        None => DEFAULT_DEVICE_TIMEOUT,
     };
+    Ok(Duration::from_secs(seconds))
+}
 
-    mount(Some(tag), mountpoint, Some("virtiofs"), flags, None::<&str>)
+/// List the tags of the virtiofs devices that have been probed, or None if
+/// the kernel is too old to list them in sysfs
+fn virtiofs_tags() -> Option<Vec<String>> {
+    let entries = fs::read_dir("/sys/fs/virtiofs").ok()?;
+    let mut tags: Vec<String> = entries
+        .flatten()
+        .filter_map(|entry| fs::read_to_string(entry.path().join("tag")).ok())
+        .map(|tag| tag.trim().to_string())
+        .collect();
+    tags.sort();
+    Some(tags)
 }
 
-fn switch_root(newroot: &str) -> Result<(), Box<dyn std::error::Error>> {
-    debugln!("Switching root to {}", newroot);
+/// Wait for the virtiofs device with the given tag to be probed. Returns
+/// false if the kernel doesn't list the tags, so we can't know.
+fn wait_for_virtiofs_tag(tag: &str, timeout: Duration) -> Result<bool, InitError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let Some(tags) = virtiofs_tags() else {
+            return Ok(false);
+        };
+        if tags.iter().any(|t| t == tag) {
+            return Ok(true);
+        }
+        if Instant::now() >= deadline {
+            return Err(InitError::message(
+                format!("waiting for virtiofs tag '{}'", tag),
+                format!(
+                    "timed out after {:?}, available tags: {}",
+                    timeout,
+                    if tags.is_empty() {
+                        "none".to_string()
+                    } else {
+                        tags.join(", ")
+                    }
+                ),
+            ));
+        }
+        sleep(Duration::from_millis(50));
+    }
+}
+
+/// Mount a directory shared by the host, with fstype one of TAG_FSTYPES.
+///
+/// The devices are probed asynchronously, so we wait up to timeout for the
+/// tag to show up. When we can't see virtiofs tags in sysfs, and for 9p,
+/// the mount is retried with backoff while it fails as if the tag was missing.
+fn mount_tag(
+    fstype: &str,
+    tag: &str,
+    mountpoint: &str,
+    options: &MountOptions,
+    timeout: Duration,
+) -> Result<(), InitError> {
+    let deadline = Instant::now() + timeout;
+    let tag_present = fstype == "virtiofs" && wait_for_virtiofs_tag(tag, timeout)?;
+    mount_waited_tag(fstype, tag, mountpoint, options, tag_present, deadline)
+}
+
+/// Mount a tag that has been waited for with wait_for_virtiofs_tag() if it
+/// is a virtiofs one. If the tag wasn't seen to be present, the mount is
+/// retried until deadline.
+fn mount_waited_tag(
+    fstype: &str,
+    tag: &str,
+    mountpoint: &str,
+    options: &MountOptions,
+    tag_present: bool,
+    deadline: Instant,
+) -> Result<(), InitError> {
+    let mut delay = Duration::from_millis(10);
+    loop {
+        let result = match fstype {
+            "9p" => mount_9p(tag, mountpoint, options),
+            _ => mount_virtiofs(tag, mountpoint, options),
+        };
+        match result {
+            // virtiofs fails with EINVAL and 9p with ENOENT for unknown tags
+            Err(Errno::ENOENT | Errno::EINVAL) if !tag_present && Instant::now() < deadline => {
+                debugln!("Mounting {} failed, retrying in {:?}", tag, delay);
+                sleep(delay);
+                delay = (delay * 2).min(Duration::from_millis(500));
+            }
+            Ok(()) => return Ok(()),
+            Err(errno) => {
+                let err = InitError::new(
+                    format!("mounting {} tag '{}' at {}", fstype, tag, mountpoint),
+                    Cause::Errno(errno),
+                );
+                return Err(match errno {
+                    Errno::ENOENT | Errno::EINVAL if !tag_present => {
+                        err.with_hint("tag not present")
+                    }
+                    _ => err,
+                });
+            }
+        }
+    }
+}
+
+/// List the virtiofs tags once no new devices have been probed for
+/// TAG_SETTLE_TIME, or when timeout has passed
+fn settled_virtiofs_tags(timeout: Duration) -> Option<Vec<String>> {
+    let deadline = Instant::now() + timeout;
+    let mut tags = virtiofs_tags()?;
+    let mut unchanged_since = Instant::now();
+    loop {
+        let now = Instant::now();
+        if now >= deadline || now - unchanged_since >= TAG_SETTLE_TIME {
+            return Some(tags);
+        }
+        sleep(Duration::from_millis(50));
+        let current = virtiofs_tags()?;
+        if current != tags {
+            debugln!("virtiofs tags changed: {}", current.join(", "));
+            tags = current;
+            unchanged_since = Instant::now();
+        }
+    }
+}
+
+/// Mount every virtiofs tag the VMM exposes, except the ones in skip_tags,
+/// at /run/mnt/<tag>. Tags ending with ".ro" are mounted read-only, without
+/// the suffix in the mount path. Paths in existing are already mounted, and
+/// the paths used are added to mounted.
+fn mount_all_virtiofs(
+    skip_tags: &[&str],
+    existing: &[String],
+    mounted: &mut Vec<String>,
+    timeout: Duration,
+) -> Result<(), InitError> {
+    let Some(tags) = settled_virtiofs_tags(timeout) else {
+        warnln!("Kernel doesn't list virtiofs tags in sysfs, can't mount all tags");
+        return Ok(());
+    };
+
+    for tag in tags.iter().filter(|tag| !skip_tags.contains(&tag.as_str())) {
+        let (name, read_only) = match tag.strip_suffix(".ro") {
+            Some(name) => (name, true),
+            None => (tag.as_str(), false),
+        };
+        if let Err(e) = validate_tag(tag).and_then(|_| validate_tag(name)) {
+            warnln!("Not mounting virtiofs tag: {}", e);
+            continue;
+        }
+        let mount_path = format!("/run/mnt/{}", name);
+        // Like for tags "data" and "data.ro", or "data.ro" with an explicit
+        // virtintrd.mount=data
+        if mounted.contains(&mount_path) {
+            warnln!(
+                "Not mounting virtiofs tag '{}', {} is already used by another tag",
+                tag,
+                mount_path
+            );
+            continue;
+        }
+        if !existing.contains(&mount_path) {
+            mkdir_p(&mount_path)?;
+            mount_tag(
+                "virtiofs",
+                tag,
+                &mount_path,
+                &MountOptions::new(read_only),
+                timeout,
+            )?;
+        }
+        mounted.push(mount_path);
+    }
 
-    chdir(newroot)?;
-    let _old_root = fs::File::open("/")?;
-    move_mount(".", "/")?;
-    chroot(".")?;
-    chdir("/")?;
     Ok(())
 }
 
-fn load_kernel_module(module_path: &Path) -> io::Result<()> {
-    debugln!("Loading module: {}", module_path.display());
-    let module_data = fs::read(module_path)?;
+/// The mount points of everything mounted, from /proc/self/mounts
+fn mount_points() -> Vec<String> {
+    fs::read_to_string("/proc/self/mounts")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Wait for a block device to appear, as the driver probes devices
+/// asynchronously
+fn wait_for_block_device(spec: &str, timeout: Duration) -> Result<PathBuf, InitError> {
+    debugln!("Waiting for root device {}", spec);
+    let action = || format!("waiting for root device {}", spec);
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(device) = block::find_device(spec).context(action)? {
+            debugln!("Found root device {}", device.display());
+            return Ok(device);
+        }
+        if Instant::now() >= deadline {
+            return Err(InitError::message(
+                action(),
+                format!("timed out after {:?}", timeout),
+            ));
+        }
+        sleep(Duration::from_millis(50));
+    }
+}
 
-    unsafe {
-        let result = libc::syscall(
-            libc::SYS_init_module,
-            module_data.as_ptr(),
-            module_data.len(),
-            b"\0".as_ptr() as *const libc::c_char,
-        );
-        if result != 0 {
-            return Err(io::Error::last_os_error());
+fn mount_block(
+    device: &Path,
+    mountpoint: &str,
+    fstype: &str,
+    options: &MountOptions,
+) -> Result<(), InitError> {
+    let data = options.data();
+    debugln!(
+        "Mounting {} ({}) at {} (flags: {:?}, data: {})",
+        device.display(),
+        fstype,
+        mountpoint,
+        options.flags,
+        data.as_deref().unwrap_or("")
+    );
+
+    mount(
+        Some(device),
+        mountpoint,
+        Some(fstype),
+        options.flags,
+        data.as_deref(),
+    )
+    .context(|| {
+        format!(
+            "mounting {} ({}) at {}",
+            device.display(),
+            fstype,
+            mountpoint
+        )
+    })
+}
+
+/// Detect the filesystem type of a block device, or the image attached to it
+fn probe_fstype(device: &Path, description: &Path) -> Result<&'static str, InitError> {
+    let action = || format!("probing filesystem in {}", description.display());
+    match block::probe(device).context(action)? {
+        Some(sb) => Ok(sb.fstype),
+        None => Err(InitError::message(action(), "unknown filesystem type")),
+    }
+}
+
+/// Mount the root filesystem at mountpoint. If force_read_only is set it is
+/// mounted read-only regardless of the rw and rootflags parameters.
+fn mount_root(cmdline: &Cmdline, mountpoint: &str, force_read_only: bool) -> Result<(), InitError> {
+    let read_only = cmdline_root_read_only(cmdline);
+    let timeout = cmdline_get_timeout(cmdline)?;
+    let (rootflags_key, rootflags) = match cmdline.get_option("rootflags") {
+        Some(flags) => (format!("{}rootflags", cmdline::PREFIX), flags),
+        None => (
+            "rootflags".to_string(),
+            cmdline.get("rootflags").unwrap_or_default(),
+        ),
+    };
+
+    match cmdline_get_root(cmdline) {
+        RootSource::Tag { fstype, tag } => {
+            validate_tag(tag).context(|| "root filesystem".to_string())?;
+            let mut options =
+                MountOptions::parse(fstype, rootflags, read_only).context(|| rootflags_key)?;
+            if force_read_only {
+                options.flags.insert(MsFlags::MS_RDONLY);
+            }
+            mount_tag(fstype, tag, mountpoint, &options, timeout)?;
+        }
+        RootSource::Block(spec) => {
+            let device = wait_for_block_device(spec, timeout)?;
+            let fstype = match cmdline.get("rootfstype").filter(|t| !t.is_empty()) {
+                Some(fstype) => fstype,
+                None => probe_fstype(&device, &device)?,
+            };
+            let mut options =
+                MountOptions::parse(fstype, rootflags, read_only).context(|| rootflags_key)?;
+            if force_read_only {
+                options.flags.insert(MsFlags::MS_RDONLY);
+            }
+            mount_block(&device, mountpoint, fstype, &options)?;
+        }
+        RootSource::Image { tag, path } => {
+            let param = format!("{}rootimage", cmdline::PREFIX);
+            validate_tag(tag).context(|| param.clone())?;
+            if path.split('/').any(|component| component == "..") {
+                return Err(InitError::message(
+                    param,
+                    format!("image path '{}' must not contain '..'", path),
+                ));
+            }
+            let image_dir = "/rootimage.src";
+            mkdir_p(image_dir)?;
+            mount_tag(
+                "virtiofs",
+                tag,
+                image_dir,
+                &MountOptions::new(true),
+                timeout,
+            )?;
+
+            let image = Path::new(image_dir).join(path.trim_start_matches('/'));
+            debugln!("Attaching {} to a loop device", image.display());
+            let (device, _loop_file) = loopdev::attach_read_only(&image)
+                .context(|| format!("attaching {} to a loop device", image.display()))?;
+
+            let fstype = match cmdline.get("rootfstype").filter(|t| !t.is_empty()) {
+                Some(fstype) => fstype,
+                None => probe_fstype(&device, &image)?,
+            };
+            // Images are always read-only, use virtintrd.overlay to write
+            let mut options =
+                MountOptions::parse(fstype, rootflags, true).context(|| rootflags_key)?;
+            options.flags.insert(MsFlags::MS_RDONLY);
+            mount_block(&device, mountpoint, fstype, &options)?;
         }
     }
 
     Ok(())
 }
 
-/// Load all kernel modules from a directory, in named order
-fn load_kernel_modules(modules_dir: &str) -> io::Result<()> {
-    let dir_path = Path::new(modules_dir);
-    if !dir_path.exists() {
-        return Ok(());
+/// Mount a writable overlayfs at newroot, with lower as the read-only lower
+/// layer and the upper layer on a tmpfs, so all writes are lost at power off
+fn mount_overlay(lower: &str, newroot: &str, size: Option<&str>) -> Result<(), InitError> {
+    let overlay_dir = "/rootfs.overlay";
+    mkdir_p(overlay_dir)?;
+
+    let tmpfs_options = match size {
+        Some(size) => format!("mode=0755,size={}", size),
+        None => "mode=0755".to_string(),
+    };
+    debugln!(
+        "Mounting overlay tmpfs at {} ({})",
+        overlay_dir,
+        tmpfs_options
+    );
+    mount(
+        Some("tmpfs"),
+        overlay_dir,
+        Some("tmpfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        Some(tmpfs_options.as_str()),
+    )
+    .context(|| format!("mounting tmpfs at {}", overlay_dir))?;
+
+    let upper = format!("{}/upper", overlay_dir);
+    let work = format!("{}/work", overlay_dir);
+    mkdir_p(&upper)?;
+    mkdir_p(&work)?;
+
+    let options = format!("lowerdir={},upperdir={},workdir={}", lower, upper, work);
+    debugln!("Mounting overlay at {} ({})", newroot, options);
+    mount(
+        Some("overlay"),
+        newroot,
+        Some("overlay"),
+        MsFlags::empty(),
+        Some(options.as_str()),
+    )
+    .context(|| format!("mounting overlay at {}", newroot))
+}
+
+/// Move the API filesystems into the new root, or unmount them if it has
+/// nowhere to put them
+fn move_api_mounts(newroot: &str) -> Result<(), InitError> {
+    let surviving_mounts = ["/run", "/dev", "/proc", "/sys", "/tmp"];
+    for mount_point in surviving_mounts {
+        let dest = format!("{}{}", newroot, mount_point);
+        if Path::new(&dest).exists() {
+            move_mount(mount_point, &dest)?;
+        } else {
+            umount(mount_point).context(|| format!("unmounting {}", mount_point))?;
+        }
     }
+    Ok(())
+}
 
-    let entries = fs::read_dir(dir_path)?;
-    let mut module_paths = Vec::new();
+fn switch_root(newroot: &str) -> Result<(), InitError> {
+    debugln!("Switching root to {}", newroot);
 
-    for entry in entries {
-        let entry = entry?;
-        let path = entry.path();
+    chdir(newroot).context(|| format!("changing directory to {}", newroot))?;
+    let _old_root = fs::File::open("/").context(|| "opening the old root".to_string())?;
+    move_mount(".", "/")?;
+    chroot(".").context(|| format!("changing root to {}", newroot))?;
+    chdir("/").context(|| "changing directory to /".to_string())?;
+    Ok(())
+}
 
-        if path.is_file() {
-            if let Some(ext) = path.extension() {
-                if ext == "ko" {
-                    module_paths.push(path);
+/// Reap children until there are none left, or until timeout. Returns
+/// false if some are still running.
+fn reap_children(timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) => {
+                if Instant::now() >= deadline {
+                    return false;
                 }
+                sleep(Duration::from_millis(10));
             }
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(_) => return true, // ECHILD, nothing left
+        }
+    }
+}
+
+/// Ask all remaining processes to exit, escalating to SIGKILL, and reap them
+fn kill_all_processes() {
+    debugln!("Terminating remaining processes");
+    let _ = kill(Pid::from_raw(-1), Signal::SIGTERM);
+    if reap_children(Duration::from_secs(2)) {
+        return;
+    }
+
+    debugln!("Killing remaining processes");
+    let _ = kill(Pid::from_raw(-1), Signal::SIGKILL);
+    // Processes stuck in the kernel, like on a dead virtiofs mount, can't
+    // be killed, so don't wait for them forever
+    if !reap_children(Duration::from_secs(5)) {
+        warnln!("Some processes didn't exit, powering off anyway");
+    }
+}
+
+/// Cleanly shut down the VM: stop everything, unmount and power off
+fn power_off(mounts: &[String]) -> ! {
+    kill_all_processes();
+    sync();
+
+    for mount_point in mounts.iter().rev() {
+        debugln!("Unmounting {}", mount_point);
+        if let Err(e) = umount(mount_point.as_str()) {
+            warnln!("Failed to unmount {}: {}", mount_point, e);
         }
     }
 
-    // Sort modules by filename to ensure correct loading order
-    module_paths.sort_by(|a, b| {
-        let a_name = a.file_name().and_then(|n| n.to_str()).unwrap_or("");
-        let b_name = b.file_name().and_then(|n| n.to_str()).unwrap_or("");
-        a_name.cmp(b_name)
-    });
+    // Leave a writable root filesystem clean
+    debugln!("Remounting / read-only");
+    if let Err(e) = mount(
+        None::<&str>,
+        "/",
+        None::<&str>,
+        MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
+        None::<&str>,
+    ) {
+        warnln!("Failed to remount / read-only: {}", e);
+    }
+    sync();
 
-    for path in module_paths {
-        match load_kernel_module(&path) {
-            Ok(_) => {}
+    debugln!("Powering off");
+    let err = reboot(RebootMode::RB_POWER_OFF).unwrap_err();
+    errorln!("Failed to power off: {}", err);
+    // Returning from PID 1 panics the kernel, which at least stops the VM
+    std::process::exit(1);
+}
+
+/// Find the device node of the virtio-serial port with the given name
+fn find_virtio_port(name: &str) -> Option<PathBuf> {
+    let entries = fs::read_dir("/sys/class/virtio-ports").ok()?;
+    for entry in entries.flatten() {
+        let port_name = fs::read_to_string(entry.path().join("name")).unwrap_or_default();
+        if port_name.trim() == name {
+            return Some(Path::new("/dev").join(entry.file_name()));
+        }
+    }
+    None
+}
+
+/// Send the exit status of the init program to the host, if it asked for it
+/// by adding a virtio-serial port named EXIT_STATUS_PORT. The status is a
+/// single line, either "exit <code>", "signal <number>" or "boot-failed".
+fn report_exit_status(status: &str) {
+    let Some(port) = find_virtio_port(EXIT_STATUS_PORT) else {
+        debugln!("No {} port, not reporting exit status", EXIT_STATUS_PORT);
+        return;
+    };
+
+    debugln!("Reporting '{}' to {}", status, port.display());
+    if let Err(e) = fs::write(&port, format!("{}\n", status)) {
+        warnln!("Failed to report exit status to {}: {}", port.display(), e);
+    }
+}
+
+/// Convert a string for passing to execve
+fn c_string(s: &str) -> Result<CString, InitError> {
+    CString::new(s)
+        .map_err(|_| InitError::message(format!("passing '{}' to init", s), "contains a nul byte"))
+}
+
+/// Build the environment for the init program: sane defaults, with TERM
+/// matching the console, overridden by env.NAME=value parameters
+fn init_environment(cmdline: &Cmdline, console: &Path) -> Result<Vec<CString>, InitError> {
+    let mut env = vec![
+        (
+            "PATH",
+            "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin",
+        ),
+        ("HOME", "/root"),
+        ("TERM", tty::term(console)),
+    ];
+
+    for (name, value) in cmdline_get_env(cmdline) {
+        match env.iter_mut().find(|(n, _)| *n == name) {
+            Some(var) => var.1 = value,
+            None => env.push((name, value)),
+        }
+    }
+
+    let mut result = Vec::new();
+    for (name, value) in env {
+        debugln!("Setting {}={}", name, value);
+        result.push(c_string(&format!("{}={}", name, value))?);
+    }
+    Ok(result)
+}
+
+/// Build the arguments for the init program, with its basename as argv[0]
+fn init_argv<'a>(
+    init_program: &str,
+    args: impl Iterator<Item = &'a str>,
+) -> Result<Vec<CString>, InitError> {
+    let init_name = Path::new(init_program)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or(init_program);
+
+    let mut argv = vec![c_string(init_name)?];
+    for arg in args {
+        debugln!("Init argument: {}", arg);
+        argv.push(c_string(arg)?);
+    }
+    Ok(argv)
+}
+
+/// Replace this process with the init program, only returning on failure
+fn exec_init(init_program: &str, args: &[CString], env: &[CString]) -> InitError {
+    let errno = match c_string(init_program) {
+        Ok(init_path) => execve(&init_path, args, env).unwrap_err(),
+        Err(err) => return err,
+    };
+    let err = InitError::new(format!("executing {}", init_program), Cause::Errno(errno));
+    match errno {
+        Errno::ENOENT => err.with_hint("not found in the root filesystem"),
+        _ => err,
+    }
+}
+
+/// Give the init program the console as its controlling terminal
+fn set_controlling_terminal(console: &Path) {
+    debugln!("Using {} as controlling terminal", console.display());
+    if let Err(e) = tty::set_controlling_terminal(console) {
+        warnln!(
+            "Failed to make {} the controlling terminal: {}",
+            console.display(),
+            e
+        );
+    }
+}
+
+/// Run the init program as a child process, staying PID 1 to reap all
+/// children until it exits, then power off
+fn supervise(
+    init_path: &CString,
+    args: &[CString],
+    env: &[CString],
+    console: &Path,
+    mounts: &[String],
+) -> ! {
+    let workload = match unsafe { fork() } {
+        Ok(ForkResult::Child) => {
+            set_controlling_terminal(console);
+            let _ = execve(init_path, args, env);
+            errorln!("Failed to execute {}", init_path.to_string_lossy());
+            std::process::exit(127);
+        }
+        Ok(ForkResult::Parent { child }) => child,
+        Err(e) => {
+            errorln!("Failed to fork init: {}", e);
+            power_off(mounts);
+        }
+    };
+    debugln!("Started init as pid {}", workload);
+
+    let status = loop {
+        match wait() {
+            Ok(WaitStatus::Exited(pid, code)) if pid == workload => {
+                debugln!("Init exited with status {}", code);
+                break format!("exit {}", code);
+            }
+            Ok(WaitStatus::Signaled(pid, signal, _)) if pid == workload => {
+                debugln!("Init killed by signal {}", signal);
+                break format!("signal {}", signal as i32);
+            }
+            Ok(_) | Err(Errno::EINTR) => {}
             Err(e) => {
-                eprintln!("Failed to load module {}: {}", path.display(), e);
-                // Continue loading other modules even if one fails
+                errorln!("Failed to wait for children: {}", e);
+                power_off(mounts);
             }
         }
+    };
+
+    report_exit_status(&status);
+    power_off(mounts);
+}
+
+/// Start the init program after switching root, supervised if asked for
+/// with virtintrd.supervise and otherwise replacing this process. The
+/// arguments are the ones from the command line, unless args is given.
+fn start_init(
+    cmdline: &Cmdline,
+    init_program: &str,
+    args: Option<&[&str]>,
+    console: &Path,
+    mounts: &[String],
+) -> Result<Infallible, InitError> {
+    debugln!("Executing init: {}", init_program);
+
+    let init_path = c_string(init_program)?;
+    let argv = match args {
+        Some(args) => init_argv(init_program, args.iter().copied())?,
+        None => init_argv(
+            init_program,
+            cmdline
+                .get_all("init.arg")
+                .chain(cmdline.init_args().iter().map(String::as_str)),
+        )?,
+    };
+    let env = init_environment(cmdline, console)?;
+
+    if cmdline.has_option("supervise") {
+        supervise(&init_path, &argv, &env, console, mounts);
     }
 
-    Ok(())
+    set_controlling_terminal(console);
+
+    Err(exec_init(init_program, &argv, &env))
+}
+
+/// Mount the additional filesystems below /run/mnt: the virtintrd.mount
+/// ones without a path, and with virtintrd.mount-all every other virtiofs
+/// tag. The ones that are mounted already, when booting is continued from
+/// the rescue console, are left alone. Returns the paths of all of them.
+fn mount_run_mnt(cmdline: &Cmdline) -> Result<Vec<String>, InitError> {
+    let timeout = cmdline_get_timeout(cmdline).phase(Phase::Cmdline)?;
+    let additional_mounts = cmdline_get_mounts(cmdline).phase(Phase::Cmdline)?;
+    let existing = mount_points();
+
+    let mut mounted = Vec::new();
+    for spec in additional_mounts
+        .iter()
+        .filter(|spec| spec.target.is_none())
+    {
+        let mount_path = format!("/run/mnt/{}", spec.tag);
+        if !existing.contains(&mount_path) {
+            mkdir_p(&mount_path).phase(Phase::Mounts)?;
+            mount_tag(spec.fstype, spec.tag, &mount_path, &spec.options, timeout)
+                .phase(Phase::Mounts)?;
+        }
+        mounted.push(mount_path);
+    }
+
+    if cmdline.has_option("mount-all") {
+        let mut used_tags: Vec<&str> = additional_mounts.iter().map(|spec| spec.tag).collect();
+        match cmdline_get_root(cmdline) {
+            RootSource::Tag { tag, .. } | RootSource::Image { tag, .. } => used_tags.push(tag),
+            RootSource::Block(_) => {}
+        }
+        mount_all_virtiofs(&used_tags, &existing, &mut mounted, timeout).phase(Phase::Mounts)?;
+    }
+
+    Ok(mounted)
+}
+
+/// Switch to the root filesystem mounted at newroot and start init, like
+/// at the end of booting. mounted are the additional mounts done so far,
+/// and the virtintrd.mount ones with a path in the new root are added.
+fn finish_boot(
+    cmdline: &Cmdline,
+    newroot: &str,
+    init_program: &str,
+    init_args: Option<&[&str]>,
+    mut mounted: Vec<String>,
+) -> Result<Infallible, InitError> {
+    let timeout = cmdline_get_timeout(cmdline).phase(Phase::Cmdline)?;
+    let additional_mounts = cmdline_get_mounts(cmdline).phase(Phase::Cmdline)?;
+
+    // Find the console while /sys is still mounted where we expect it
+    let console = tty::console_device();
+
+    // Mounts with an explicit path go in after switching root, so they can
+    // be placed below /run or /tmp too, and so that symlinks in the path are
+    // resolved in the new root rather than in the initrd. The virtiofs tags
+    // are only listed in /sys before it is moved, so wait for them now.
+    let mut targeted_mounts = Vec::new();
+    for spec in additional_mounts.iter() {
+        if let Some(target) = spec.target {
+            let tag_present = spec.fstype == "virtiofs"
+                && wait_for_virtiofs_tag(spec.tag, timeout).phase(Phase::Mounts)?;
+            targeted_mounts.push((spec, target, tag_present));
+        }
+    }
+
+    move_api_mounts(newroot).phase(Phase::SwitchRoot)?;
+    switch_root(newroot).phase(Phase::SwitchRoot)?;
+
+    for (spec, target, tag_present) in targeted_mounts {
+        mkdir_p(target)
+            .map_err(|err| match err.cause {
+                Cause::Errno(Errno::EROFS) => err.with_hint(
+                    "the root filesystem is read-only, create the directory in it \
+                     or boot with rw or virtintrd.overlay",
+                ),
+                _ => err,
+            })
+            .phase(Phase::Mounts)?;
+        let deadline = Instant::now() + timeout;
+        mount_waited_tag(
+            spec.fstype,
+            spec.tag,
+            target,
+            &spec.options,
+            tag_present,
+            deadline,
+        )
+        .phase(Phase::Mounts)?;
+        mounted.push(target.to_string());
+    }
+
+    start_init(cmdline, init_program, init_args, &console, &mounted).phase(Phase::Exec)
 }
 
-fn do_init() -> Result<(), Box<dyn std::error::Error>> {
+fn do_init() -> Result<(), InitError> {
     // Create required directories
     let dirs = ["/sysroot", "/sys", "/dev", "/proc", "/run", "/tmp"];
     for dir in &dirs {
@@ -263,60 +1139,107 @@ fn do_init() -> Result<(), Box<dyn std::error::Error>> {
 
     mkdir_p("/run/mnt")?;
 
-    let cmdline = read_cmdline()?;
+    let cmdline = Cmdline::read()
+        .context(|| "reading /proc/cmdline".to_string())
+        .phase(Phase::Cmdline)?;
+
+    log::init(&cmdline);
 
-    if cmdline_get(&cmdline, "debug").is_some() {
-        set_debug(true);
+    for param in cmdline.deprecated_params() {
+        warnln!(
+            "'{}' is deprecated, use '{}{}'",
+            param,
+            cmdline::PREFIX,
+            param
+        );
     }
 
-    create_static_devices()?;
+    create_static_devices().phase(Phase::Devices)?;
 
-    load_kernel_modules("/usr/lib/modules")?;
+    let module_options = modules::ModuleOptions::read(&cmdline);
+    modules::load_modules(&module_options).phase(Phase::Modules)?;
 
-    let rootfs_tag = cmdline_get(&cmdline, "rootfs").unwrap_or("rootfs");
-    mount_virtiofs(rootfs_tag, "/sysroot", true)?;
+    if cmdline_break_at(&cmdline, "pre-mount") {
+        console::run("continue booting");
+    }
 
-    let additional_mounts = cmdline_get_mounts(&cmdline);
-    for (tag, read_only) in additional_mounts {
-        let mount_path = format!("/run/mnt/{}", tag);
-        mkdir_p(&mount_path)?;
-        mount_virtiofs(tag, &mount_path, read_only)?;
+    if cmdline.has_option("overlay") {
+        // The rootfs is only the lower layer, so it is never written to
+        let lower = "/rootfs.lower";
+        mkdir_p(lower).phase(Phase::RootMount)?;
+        mount_root(&cmdline, lower, true).phase(Phase::RootMount)?;
+        let size = cmdline.get_option("overlay").filter(|s| !s.is_empty());
+        mount_overlay(lower, "/sysroot", size).phase(Phase::RootMount)?;
+    } else {
+        mount_root(&cmdline, "/sysroot", false).phase(Phase::RootMount)?;
     }
 
-    // Move mounts to new root if mountpoint exists
-    let surviving_mounts = ["/run", "/dev", "/proc", "/sys", "/tmp"];
-    for mount_point in surviving_mounts {
-        let dest = format!("/sysroot{}", mount_point);
-        if Path::new(&dest).exists() {
-            move_mount(mount_point, &dest)?;
-        } else {
-            umount(mount_point)?;
-        }
+    let mounted = mount_run_mnt(&cmdline)?;
+
+    if cmdline_break_at(&cmdline, "pre-pivot") {
+        console::run("continue booting");
     }
 
-    switch_root("/sysroot")?;
+    let init_program = cmdline.get_option("init").unwrap_or("/bin/sh");
+    match finish_boot(&cmdline, "/sysroot", init_program, None, mounted)? {}
+}
 
-    let init_program = cmdline_get(&cmdline, "init").unwrap_or("/bin/sh");
-    debugln!("Executing init: {}", init_program);
+fn main() {
+    if let Err(err) = do_init() {
+        emergency::emergency(&err);
+    }
+}
 
-    // Get the basename for argv[0]
-    let init_name = Path::new(init_program)
-        .file_name()
-        .and_then(|n| n.to_str())
-        .unwrap_or(init_program);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let init_path = CString::new(init_program)?;
-    let init_arg = CString::new(init_name)?;
-    let args = vec![&init_arg];
+    #[test]
+    fn valid_tags() {
+        for tag in [
+            "rootfs",
+            "my-tag_1",
+            "a.ro",
+            ".hidden",
+            &"a".repeat(MAX_TAG_LEN),
+        ] {
+            assert_eq!(validate_tag(tag), Ok(()), "{:?}", tag);
+        }
+    }
 
-    execv(&init_path, &args)?;
-    eprintln!("Failed to execute {}", init_program);
-    Ok(())
-}
+    #[test]
+    fn invalid_tags() {
+        for tag in [
+            "",
+            ".",
+            "..",
+            "../../etc",
+            "a/b",
+            "/",
+            "a\0b",
+            "a b",
+            "a\nb",
+            "tag:x",
+            "t\u{e4}g",
+            &"a".repeat(MAX_TAG_LEN + 1),
+        ] {
+            assert!(validate_tag(tag).is_err(), "{:?}", tag);
+        }
+    }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    if let Err(err) = do_init() {
-        eprintln!("Unexpected error: {}", err);
+    #[test]
+    fn valid_mount_paths() {
+        for path in ["/src", "/run/mnt/x", "/a/./b", "/a..b/c", "/a/"] {
+            assert_eq!(validate_mount_path(path), Ok(()), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn invalid_mount_paths() {
+        for path in [
+            "", "relative", "./a", "/a/../b", "/..", "/a/..", "/", "//", "/./", "/.",
+        ] {
+            assert!(validate_mount_path(path).is_err(), "{:?}", path);
+        }
     }
-    Ok(())
 }
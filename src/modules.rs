@@ -0,0 +1,984 @@
+//! Loading kernel modules
+//!
+//! Modules are loaded with finit_module from the file, and compressed ones
+//! are decompressed by the kernel if it supports that. Otherwise we
+//! decompress them ourselves and load them with init_module.
+//!
+//! The initrd has the modules.dep, modules.softdep and modules.alias files
+//! of the kernel for the modules it includes, and the modules listed in
+//! modules-load.d are loaded together with everything they depend on.
+//! Modules in modules.builtin, or already in /sys/module, are skipped.
+//! After that the modules for the devices that are present are loaded, by
+//! matching their modaliases against modules.alias, like udev would.
+//!
+//! Module parameters come from modprobe.d style "options" lines in the
+//! initrd, and from <module>.<param>=<value> kernel command line arguments,
+//! which the kernel itself only applies to built-in modules.
+
+use crate::cmdline::{self, Cmdline};
+use crate::error::{Context, InitError};
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+// From linux/module.h
+const MODULE_INIT_COMPRESSED_FILE: libc::c_uint = 4;
+
+/// Where the initrd has the kernel modules and their index files
+const MODULES_DIR: &str = "/usr/lib/modules";
+
+/// Where module options are read from, in modprobe.d format
+const MODPROBE_CONF_DIR: &str = "/etc/modprobe.d";
+
+/// Where the lists of modules to load are, in modules-load.d format
+const MODULES_LOAD_DIR: &str = "/etc/modules-load.d";
+
+/// Prefixes of dotted command line parameters that aren't module parameters
+const NON_MODULE_PARAMS: &[&str] = &[cmdline::PREFIX, "init.arg", "env.", "rd."];
+
+/// The kernel treats - and _ in module and parameter names the same
+fn normalize_name(name: &str) -> String {
+    name.replace('-', "_")
+}
+
+/// The module name for a module file, like virtio_blk for virtio_blk.ko.xz
+fn module_name(path: &str) -> String {
+    let name = Path::new(path)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    normalize_name(name.split_once(".ko").map_or(name, |(name, _)| name))
+}
+
+/// The config files in a directory, in the order they apply
+fn conf_files(dir: &str) -> Vec<PathBuf> {
+    let mut files: Vec<_> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "conf"))
+        .collect();
+    files.sort();
+    files
+}
+
+/// Match a module alias pattern, with the * ? and [...] of shell globs
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text) = (pattern.as_bytes(), text.as_bytes());
+    // Where to continue after the last *, to backtrack on a mismatch
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut t) = (0, 0);
+
+    while t < text.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    star = Some((p + 1, t));
+                    p += 1;
+                    continue;
+                }
+                b'?' => {
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+                b'[' => {
+                    if let Some(end) = pattern[p + 1..].iter().position(|c| *c == b']') {
+                        let class = &pattern[p + 1..p + 1 + end];
+                        let (negate, class) = match class.first() {
+                            Some(b'!' | b'^') => (true, &class[1..]),
+                            _ => (false, class),
+                        };
+                        if class_contains(class, text[t]) != negate {
+                            p += end + 2;
+                            t += 1;
+                            continue;
+                        }
+                    } else if text[t] == b'[' {
+                        p += 1;
+                        t += 1;
+                        continue;
+                    }
+                }
+                c if c == text[t] => {
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        match star {
+            Some((star_p, star_t)) => {
+                p = star_p;
+                t = star_t + 1;
+                star = Some((star_p, star_t + 1));
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+/// Check if a [...] character class, without the brackets, contains c
+fn class_contains(class: &[u8], c: u8) -> bool {
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == b'-' {
+            if (class[i]..=class[i + 2]).contains(&c) {
+                return true;
+            }
+            i += 3;
+        } else {
+            if class[i] == c {
+                return true;
+            }
+            i += 1;
+        }
+    }
+    false
+}
+
+/// Parameters to load modules with
+#[derive(Debug, Default)]
+pub struct ModuleOptions {
+    /// (module, parameters) in the order they apply, later ones win
+    options: Vec<(String, String)>,
+}
+
+impl ModuleOptions {
+    /// Read the options lines from the modprobe.d config files in the
+    /// initrd, followed by the module parameters on the kernel command line
+    pub fn read(cmdline: &Cmdline) -> Self {
+        let mut result = Self::default();
+
+        for file in conf_files(MODPROBE_CONF_DIR) {
+            let Ok(contents) = fs::read_to_string(&file) else {
+                continue;
+            };
+            for line in contents.lines() {
+                let line = line.split('#').next().unwrap_or_default();
+                let mut words = line.split_whitespace();
+                if words.next() != Some("options") {
+                    continue;
+                }
+                if let Some(module) = words.next() {
+                    let params: Vec<&str> = words.collect();
+                    result
+                        .options
+                        .push((normalize_name(module), params.join(" ")));
+                }
+            }
+        }
+
+        result.add_cmdline_params(cmdline);
+        result
+    }
+
+    /// Add the <module>.<param>=<value> parameters on the kernel command line
+    fn add_cmdline_params(&mut self, cmdline: &Cmdline) {
+        for (key, value) in cmdline.params() {
+            if NON_MODULE_PARAMS
+                .iter()
+                .any(|prefix| key.starts_with(prefix))
+            {
+                continue;
+            }
+            let Some((module, param)) = key.split_once('.') else {
+                continue;
+            };
+            let param = match value {
+                Some(value) if value.contains(char::is_whitespace) => {
+                    format!("{}=\"{}\"", param, value)
+                }
+                Some(value) => format!("{}={}", param, value),
+                None => param.to_string(),
+            };
+            self.options.push((normalize_name(module), param));
+        }
+    }
+
+    /// The parameter string for a module
+    pub fn get(&self, module: &str) -> String {
+        let params: Vec<&str> = self
+            .options
+            .iter()
+            .filter(|(m, _)| m == module)
+            .map(|(_, params)| params.as_str())
+            .collect();
+        params.join(" ")
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Compression {
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    /// Detect the compression of a file from its magic number
+    fn detect(file: &File) -> io::Result<Option<Compression>> {
+        let mut magic = [0u8; 6];
+        let len = file.read_at(&mut magic, 0)?;
+        let magic = &magic[..len];
+        Ok(if magic.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if magic.starts_with(b"\xfd7zXZ\0") {
+            Some(Compression::Xz)
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else {
+            None
+        })
+    }
+
+    fn decompress(self, file: File) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        match self {
+            Compression::Gzip => {
+                flate2::read::GzDecoder::new(file).read_to_end(&mut data)?;
+            }
+            Compression::Xz => {
+                lzma_rs::xz_decompress(&mut BufReader::new(file), &mut data)
+                    .map_err(invalid_data)?;
+            }
+            Compression::Zstd => {
+                ruzstd::decoding::StreamingDecoder::new(file)
+                    .map_err(invalid_data)?
+                    .read_to_end(&mut data)?;
+            }
+        }
+        Ok(data)
+    }
+}
+
+fn invalid_data(err: impl fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+fn finit_module(file: &File, params: &CString, flags: libc::c_uint) -> io::Result<()> {
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_finit_module,
+            file.as_raw_fd(),
+            params.as_ptr(),
+            flags,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn init_module(data: &[u8], params: &CString) -> io::Result<()> {
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_init_module,
+            data.as_ptr(),
+            data.len(),
+            params.as_ptr(),
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Load a kernel module, which may be compressed with gzip, xz or zstd,
+/// with a space separated parameter string
+pub fn load_module(path: &Path, params: &str) -> io::Result<()> {
+    debugln!("Loading module: {} {}", path.display(), params);
+    let params = CString::new(params).map_err(invalid_data)?;
+    let file = File::open(path)?;
+
+    let Some(compression) = Compression::detect(&file)? else {
+        return finit_module(&file, &params, 0);
+    };
+    match finit_module(&file, &params, MODULE_INIT_COMPRESSED_FILE) {
+        // Older kernels don't know the flag, and the kernel can only
+        // decompress the format it was configured for, if any
+        Err(e) if matches!(e.raw_os_error(), Some(libc::EINVAL | libc::EOPNOTSUPP)) => {
+            debugln!(
+                "Kernel can't load {:?} compressed module, decompressing it",
+                compression
+            );
+            init_module(&compression.decompress(file)?, &params)
+        }
+        result => result,
+    }
+}
+
+/// A module in the initrd
+#[derive(Debug)]
+struct ModuleEntry {
+    path: PathBuf,
+    /// All modules it needs, the ones needed by the others listed last
+    dependencies: Vec<String>,
+}
+
+/// Modules to load before and after a module, if they are available
+#[derive(Debug, Default)]
+struct SoftDependencies {
+    pre: Vec<String>,
+    post: Vec<String>,
+}
+
+/// The index of the modules in the initrd, from the subset of the kernel's
+/// modules.dep, modules.softdep and modules.alias files that is included
+#[derive(Debug, Default)]
+pub struct ModuleIndex {
+    modules: HashMap<String, ModuleEntry>,
+    softdeps: HashMap<String, SoftDependencies>,
+    /// (pattern, module)
+    aliases: Vec<(String, String)>,
+    /// The modules built into the kernel
+    builtin: HashSet<String>,
+}
+
+/// Read an index file, which is empty if the initrd doesn't have it
+fn read_index_file(dir: &Path, name: &str) -> io::Result<String> {
+    match fs::read_to_string(dir.join(name)) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+        result => result,
+    }
+}
+
+impl ModuleIndex {
+    pub fn read(dir: &Path) -> io::Result<Self> {
+        Ok(Self::parse(
+            dir,
+            &read_index_file(dir, "modules.dep")?,
+            &read_index_file(dir, "modules.softdep")?,
+            &read_index_file(dir, "modules.alias")?,
+            &read_index_file(dir, "modules.builtin")?,
+        ))
+    }
+
+    /// Parse the contents of the index files, with module paths in
+    /// modules.dep relative to dir
+    fn parse(dir: &Path, dep: &str, softdep: &str, alias: &str, builtin: &str) -> Self {
+        let mut index = Self::default();
+
+        for line in dep.lines() {
+            let Some((file, dependencies)) = line.split_once(':') else {
+                continue;
+            };
+            index.modules.insert(
+                module_name(file),
+                ModuleEntry {
+                    path: dir.join(file),
+                    dependencies: dependencies.split_whitespace().map(module_name).collect(),
+                },
+            );
+        }
+
+        // softdep <module> pre: <module>... post: <module>...
+        for line in softdep.lines() {
+            let mut words = line.split_whitespace();
+            let (Some("softdep"), Some(module)) = (words.next(), words.next()) else {
+                continue;
+            };
+            let softdeps = index.softdeps.entry(normalize_name(module)).or_default();
+            let mut list = &mut softdeps.pre;
+            for word in words {
+                match word {
+                    "pre:" => list = &mut softdeps.pre,
+                    "post:" => list = &mut softdeps.post,
+                    name => list.push(normalize_name(name)),
+                }
+            }
+        }
+
+        // alias <pattern> <module>
+        for line in alias.lines() {
+            let mut words = line.split_whitespace();
+            if let (Some("alias"), Some(pattern), Some(module)) =
+                (words.next(), words.next(), words.next())
+            {
+                index
+                    .aliases
+                    .push((pattern.to_string(), normalize_name(module)));
+            }
+        }
+
+        index.builtin = builtin.lines().map(module_name).collect();
+
+        index
+    }
+
+    /// The modules a name refers to, either a module in the initrd or the
+    /// ones with a matching alias
+    pub fn resolve(&self, name: &str) -> Vec<String> {
+        let module = normalize_name(name);
+        if self.modules.contains_key(&module) || self.builtin.contains(&module) {
+            return vec![module];
+        }
+        self.aliased(name)
+    }
+
+    /// The modules with an alias matching a name or device modalias
+    fn aliased(&self, alias: &str) -> Vec<String> {
+        let mut modules = Vec::new();
+        for (pattern, module) in &self.aliases {
+            if glob_match(pattern, alias) && !modules.contains(module) {
+                modules.push(module.clone());
+            }
+        }
+        modules
+    }
+}
+
+/// Loads modules with their dependencies, keeping track of what is loaded
+pub struct ModuleLoader<'a> {
+    index: &'a ModuleIndex,
+    options: &'a ModuleOptions,
+    loaded: HashSet<String>,
+    /// The modules currently being loaded, to not loop on soft dependencies
+    loading: Vec<String>,
+    /// How to load a module file with parameters, load_module() unless testing
+    load_module: fn(&Path, &str) -> io::Result<()>,
+}
+
+impl<'a> ModuleLoader<'a> {
+    pub fn new(index: &'a ModuleIndex, options: &'a ModuleOptions) -> Self {
+        ModuleLoader {
+            index,
+            options,
+            loaded: HashSet::new(),
+            loading: Vec::new(),
+            load_module,
+        }
+    }
+
+    /// Load a module by name or alias, with everything it needs
+    pub fn load(&mut self, name: &str) -> Result<(), InitError> {
+        let modules = self.index.resolve(name);
+        if modules.is_empty() {
+            return Err(InitError::message(
+                format!("loading module {}", name),
+                "not in the initrd",
+            ));
+        }
+        for module in modules {
+            if self.loaded.contains(&module) || self.loading.contains(&module) {
+                continue;
+            }
+            self.loading.push(module.clone());
+            let result = self.load_with_dependencies(&module);
+            self.loading.pop();
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Load the modules for the devices that are present. This is repeated
+    /// until there are no new devices, as modules like virtio_pci add the
+    /// devices that other modules drive.
+    pub fn coldplug(&mut self) {
+        let mut seen = HashSet::new();
+        loop {
+            let modules = self.modules_for_devices(device_modaliases(), &mut seen);
+            if modules.is_empty() {
+                return;
+            }
+            for module in modules {
+                if let Err(e) = self.load(&module) {
+                    warnln!("{}", e);
+                }
+            }
+        }
+    }
+
+    /// The modules that aren't loaded yet for the devices with the given
+    /// modaliases, skipping the devices in seen and adding the others to it
+    fn modules_for_devices(
+        &self,
+        modaliases: Vec<String>,
+        seen: &mut HashSet<String>,
+    ) -> Vec<String> {
+        let mut modules = Vec::new();
+        for modalias in modaliases {
+            if !seen.insert(modalias.clone()) {
+                continue;
+            }
+            for module in self.index.aliased(&modalias) {
+                if !self.loaded.contains(&module) && !modules.contains(&module) {
+                    debugln!("Device {} needs module {}", modalias, module);
+                    modules.push(module);
+                }
+            }
+        }
+        modules
+    }
+
+    /// Load soft dependencies, which are optional so failing is not an error
+    fn load_soft_dependencies(&mut self, module: &str, dependencies: &[String]) {
+        for dependency in dependencies {
+            if let Err(e) = self.load(dependency) {
+                warnln!("Optional dependency of {} failed: {}", module, e);
+            }
+        }
+    }
+
+    /// Why a module doesn't need to be loaded, if it doesn't
+    fn already_present(&self, module: &str) -> Option<&'static str> {
+        if self.index.builtin.contains(module) {
+            return Some("built into the kernel");
+        }
+        // Built-in modules only have a directory if they have parameters,
+        // and unlike loaded ones they have no initstate
+        let sysfs = Path::new("/sys/module").join(module);
+        if sysfs.join("initstate").exists() {
+            Some("already loaded")
+        } else if sysfs.exists() {
+            Some("built into the kernel")
+        } else {
+            None
+        }
+    }
+
+    fn load_with_dependencies(&mut self, module: &str) -> Result<(), InitError> {
+        let index = self.index;
+        let action = || format!("loading module {}", module);
+        if let Some(reason) = self.already_present(module) {
+            debugln!("Module {} is {}", module, reason);
+            self.loaded.insert(module.to_string());
+            return Ok(());
+        }
+        let Some(entry) = index.modules.get(module) else {
+            return Err(InitError::message(action(), "not in the initrd"));
+        };
+        let softdeps = index.softdeps.get(module);
+
+        if let Some(softdeps) = softdeps {
+            self.load_soft_dependencies(module, &softdeps.pre);
+        }
+        for dependency in entry.dependencies.iter().rev() {
+            self.load(dependency).map_err(|e| {
+                InitError::message(action(), format!("dependency {} failed: {}", dependency, e))
+            })?;
+        }
+
+        match (self.load_module)(&entry.path, &self.options.get(module)) {
+            // Loaded by someone else since we checked
+            Err(e) if e.raw_os_error() == Some(libc::EEXIST) => {
+                debugln!("Module {} is already loaded", module);
+            }
+            result => result.context(action)?,
+        }
+        self.loaded.insert(module.to_string());
+
+        if let Some(softdeps) = softdeps {
+            self.load_soft_dependencies(module, &softdeps.post);
+        }
+        Ok(())
+    }
+}
+
+/// The modaliases of the devices the kernel knows about
+fn device_modaliases() -> Vec<String> {
+    let mut modaliases = Vec::new();
+    let mut dirs = vec![PathBuf::from("/sys/devices")];
+    while let Some(dir) = dirs.pop() {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            // Don't follow symlinks, sysfs has plenty of them pointing back up
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            if file_type.is_dir() {
+                dirs.push(entry.path());
+            } else if entry.file_name() == "modalias" {
+                if let Ok(modalias) = fs::read_to_string(entry.path()) {
+                    let modalias = modalias.trim();
+                    if !modalias.is_empty() {
+                        modaliases.push(modalias.to_string());
+                    }
+                }
+            }
+        }
+    }
+    modaliases.sort();
+    modaliases.dedup();
+    modaliases
+}
+
+/// The modules to load at boot, from the modules-load.d files in the initrd
+fn modules_to_load() -> Vec<String> {
+    let mut modules = Vec::new();
+    for file in conf_files(MODULES_LOAD_DIR) {
+        let Ok(contents) = fs::read_to_string(&file) else {
+            continue;
+        };
+        modules.extend(
+            contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with(['#', ';']))
+                .map(str::to_string),
+        );
+    }
+    modules
+}
+
+/// Load the modules listed in modules-load.d, with their dependencies, and
+/// then the ones for the devices that are present
+pub fn load_modules(options: &ModuleOptions) -> Result<(), InitError> {
+    let index = ModuleIndex::read(Path::new(MODULES_DIR))
+        .context(|| format!("reading the module index in {}", MODULES_DIR))?;
+    let mut loader = ModuleLoader::new(&index, options);
+
+    for module in modules_to_load() {
+        if let Err(e) = loader.load(&module) {
+            // Continue loading other modules even if one fails
+            errorln!("{}", e);
+        }
+    }
+    loader.coldplug();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    const MODULES_DEP: &str = "\
+kernel/fs/vt_fs.ko.xz: kernel/fs/vt_fuse.ko.zst kernel/drivers/vt-ring.ko
+kernel/fs/vt_fuse.ko.zst: kernel/drivers/vt-ring.ko
+kernel/drivers/vt-ring.ko:
+kernel/crypto/vt_crc-generic.ko.gz:
+kernel/fs/vt_post.ko:
+kernel/net/vt_app.ko: kernel/net/vt_net.ko
+kernel/net/vt_net.ko: kernel/net/vt_broken.ko
+kernel/net/vt_broken.ko:
+kernel/net/vt_missing_dep.ko: kernel/net/vt_gone.ko
+kernel/misc/vt_cycle_a.ko: kernel/misc/vt_cycle_b.ko
+kernel/misc/vt_cycle_b.ko: kernel/misc/vt_cycle_a.ko
+";
+
+    const MODULES_SOFTDEP: &str = "\
+# Soft dependencies extracted from modules themselves.
+softdep vt_fs pre: vt-crc-generic vt_absent post: vt_post
+softdep vt_post pre: vt_fs
+";
+
+    const MODULES_ALIAS: &str = "\
+# Aliases extracted from modules themselves.
+alias fs-vt_fs vt_fs
+alias vt-crc vt_crc_generic
+";
+
+    const MODULES_BUILTIN: &str = "kernel/misc/vt-builtin.ko\n";
+
+    fn index() -> ModuleIndex {
+        ModuleIndex::parse(
+            Path::new("/lib/modules"),
+            MODULES_DEP,
+            MODULES_SOFTDEP,
+            MODULES_ALIAS,
+            MODULES_BUILTIN,
+        )
+    }
+
+    thread_local! {
+        static LOADED: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// Record the module files loaded instead of loading them, and fail for
+    /// vt_broken like the kernel would for a bad module
+    fn record_load(path: &Path, params: &str) -> io::Result<()> {
+        let file = path.file_name().unwrap().to_string_lossy().into_owned();
+        if file == "vt_broken.ko" {
+            return Err(io::Error::from_raw_os_error(libc::ENOEXEC));
+        }
+        let record = if params.is_empty() {
+            file
+        } else {
+            format!("{} {}", file, params)
+        };
+        LOADED.with(|loaded| loaded.borrow_mut().push(record));
+        Ok(())
+    }
+
+    fn loader<'a>(index: &'a ModuleIndex, options: &'a ModuleOptions) -> ModuleLoader<'a> {
+        LOADED.with(|loaded| loaded.borrow_mut().clear());
+        ModuleLoader {
+            load_module: record_load,
+            ..ModuleLoader::new(index, options)
+        }
+    }
+
+    fn loaded() -> Vec<String> {
+        LOADED.with(|loaded| loaded.borrow().clone())
+    }
+
+    #[test]
+    fn module_names() {
+        assert_eq!(
+            module_name("kernel/drivers/block/virtio-blk.ko.zst"),
+            "virtio_blk"
+        );
+        assert_eq!(module_name("kernel/fs/fuse/virtiofs.ko.xz"), "virtiofs");
+        assert_eq!(module_name("crc32c-generic.ko.gz"), "crc32c_generic");
+        assert_eq!(module_name("9p.ko"), "9p");
+    }
+
+    #[test]
+    fn parse_index() {
+        let index = index();
+        let entry = &index.modules["vt_fs"];
+        assert_eq!(entry.path, Path::new("/lib/modules/kernel/fs/vt_fs.ko.xz"));
+        assert_eq!(entry.dependencies, ["vt_fuse", "vt_ring"]);
+        assert!(index.modules["vt_ring"].dependencies.is_empty());
+        assert!(index.modules.contains_key("vt_crc_generic"));
+
+        let softdeps = &index.softdeps["vt_fs"];
+        assert_eq!(softdeps.pre, ["vt_crc_generic", "vt_absent"]);
+        assert_eq!(softdeps.post, ["vt_post"]);
+        assert!(index.builtin.contains("vt_builtin"));
+
+        assert_eq!(index.resolve("vt-ring"), ["vt_ring"]);
+        assert_eq!(index.resolve("fs-vt_fs"), ["vt_fs"]);
+        assert_eq!(index.resolve("vt-crc"), ["vt_crc_generic"]);
+        assert_eq!(index.resolve("vt-builtin"), ["vt_builtin"]);
+        assert!(index.resolve("vt_unknown").is_empty());
+    }
+
+    #[test]
+    fn load_order() {
+        let index = index();
+        let options = ModuleOptions::default();
+        let mut loader = loader(&index, &options);
+
+        loader.load("fs-vt_fs").unwrap();
+        // The missing optional vt_absent is skipped, and vt_post's soft
+        // dependency on vt_fs doesn't loop
+        assert_eq!(
+            loaded(),
+            [
+                "vt_crc-generic.ko.gz",
+                "vt-ring.ko",
+                "vt_fuse.ko.zst",
+                "vt_fs.ko.xz",
+                "vt_post.ko"
+            ]
+        );
+
+        // Everything is loaded only once
+        loader.load("vt_fuse").unwrap();
+        loader.load("vt_post").unwrap();
+        assert_eq!(loaded().len(), 5);
+    }
+
+    #[test]
+    fn softdep_cycle() {
+        let index = index();
+        let options = ModuleOptions::default();
+        let mut loader = loader(&index, &options);
+
+        loader.load("vt_post").unwrap();
+        assert_eq!(
+            loaded(),
+            [
+                "vt_crc-generic.ko.gz",
+                "vt-ring.ko",
+                "vt_fuse.ko.zst",
+                "vt_fs.ko.xz",
+                "vt_post.ko"
+            ]
+        );
+    }
+
+    #[test]
+    fn dependency_cycle() {
+        let index = index();
+        let options = ModuleOptions::default();
+        let mut loader = loader(&index, &options);
+
+        loader.load("vt_cycle_a").unwrap();
+        assert_eq!(loaded(), ["vt_cycle_b.ko", "vt_cycle_a.ko"]);
+    }
+
+    #[test]
+    fn module_params() {
+        let index = index();
+        let options = ModuleOptions {
+            options: vec![
+                ("vt_ring".to_string(), "size=4".to_string()),
+                ("vt_ring".to_string(), "debug".to_string()),
+            ],
+        };
+        let mut loader = loader(&index, &options);
+
+        loader.load("vt_fuse").unwrap();
+        assert_eq!(loaded(), ["vt-ring.ko size=4 debug", "vt_fuse.ko.zst"]);
+    }
+
+    #[test]
+    fn builtin_modules() {
+        let index = index();
+        let options = ModuleOptions::default();
+        let mut loader = loader(&index, &options);
+
+        loader.load("vt_builtin").unwrap();
+        assert!(loaded().is_empty());
+    }
+
+    #[test]
+    fn failing_dependency() {
+        let index = index();
+        let options = ModuleOptions::default();
+        let mut loader = loader(&index, &options);
+
+        assert_eq!(
+            loader.load("vt_app").unwrap_err().to_string(),
+            "loading module vt_app: dependency vt_net failed: loading module vt_net: \
+             dependency vt_broken failed: loading module vt_broken: ENOEXEC (Exec format error)"
+        );
+        assert_eq!(
+            loader.load("vt_missing_dep").unwrap_err().to_string(),
+            "loading module vt_missing_dep: dependency vt_gone failed: \
+             loading module vt_gone: not in the initrd"
+        );
+        assert_eq!(
+            loader.load("vt_unknown").unwrap_err().to_string(),
+            "loading module vt_unknown: not in the initrd"
+        );
+        assert!(loaded().is_empty());
+    }
+
+    #[test]
+    fn cmdline_module_params() {
+        let mut options = ModuleOptions::default();
+        options.add_cmdline_params(&Cmdline::parse(
+            "virtio-blk.queue_depth=64 virtio_blk.x virtintrd.debug init.arg=a \
+             env.A=b rd.break quiet fuse.name=\"a b\"",
+        ));
+        assert_eq!(options.get("virtio_blk"), "queue_depth=64 x");
+        assert_eq!(options.get("fuse"), "name=\"a b\"");
+        for module in ["virtintrd", "init", "env", "rd", "quiet"] {
+            assert_eq!(options.get(module), "", "{}", module);
+        }
+    }
+
+    #[test]
+    fn glob_patterns() {
+        let cases = [
+            (
+                "pci:v00001AF4d00001042sv*sd*bc*sc*i*",
+                "pci:v00001AF4d00001042sv00001AF4sd00001100bc01sc00i00",
+                true,
+            ),
+            (
+                "pci:v00001AF4d00001042sv*sd*bc*sc*i*",
+                "pci:v00001AF4d00001041sv00001AF4sd00001100bc02sc00i00",
+                false,
+            ),
+            (
+                "pci:v00001AF4d00001042sv*sd*bc*sc*i*",
+                "pci:v00001AF4d00001042sv00001AF4sd00001100bc01sc00",
+                false,
+            ),
+            ("virtio:d00000002v*", "virtio:d00000002v00001AF4", true),
+            ("virtio:d00000002v*", "virtio:d00000002v", true),
+            ("virtio:d00000002v*", "virtio:d00000012v00001AF4", false),
+            ("acpi*:LNRO0005:*", "acpi:LNRO0005:", true),
+            ("acpi*:LNRO0005:*", "acpi:PNP0A03:LNRO0005:", true),
+            ("acpi*:LNRO0005:*", "of:NvirtioT(null)Cvirtio,mmio", false),
+            ("platform:virtio-mmio", "platform:virtio-mmio", true),
+            ("platform:virtio-mmio", "platform:virtio-mmio0", false),
+            (
+                "cpu:type:x86,ven*fam*mod*:feature:*0081*",
+                "cpu:type:x86,ven0000fam0006mod003F:feature:,0000,0081,0086",
+                true,
+            ),
+            (
+                "usb:v*p*d*dc*dsc*dp*ic08isc0[1-6]ip50in*",
+                "usb:v0781p5581d0100dc00dsc00dp00ic08isc06ip50in00",
+                true,
+            ),
+            (
+                "usb:v*p*d*dc*dsc*dp*ic08isc0[1-6]ip50in*",
+                "usb:v0781p5581d0100dc00dsc00dp00ic08isc07ip50in00",
+                false,
+            ),
+            ("a[!0-9]b", "axb", true),
+            ("a[!0-9]b", "a5b", false),
+            ("a[^xy]b", "azb", true),
+            ("a[xyz]", "ay", true),
+            ("a[xyz]", "aw", false),
+            ("a?c", "abc", true),
+            ("a?c", "ac", false),
+            ("abc*", "abc", true),
+            ("*", "", true),
+            ("a[", "a[", true),
+        ];
+        for (pattern, text, expected) in cases {
+            assert_eq!(glob_match(pattern, text), expected, "{} {}", pattern, text);
+        }
+    }
+
+    #[test]
+    fn coldplug_aliases() {
+        let index = ModuleIndex::parse(
+            Path::new("/lib/modules"),
+            "\
+kernel/drivers/vt_pci.ko:
+kernel/drivers/vt_blk.ko.xz:
+kernel/drivers/vt_net.ko:
+",
+            "",
+            "\
+alias pci:v00001AF4d*sv*sd*bc*sc*i* vt_pci
+alias virtio:d00000002v* vt_blk
+alias virtio:d00000001v* vt_net
+alias acpi*:LNRO0005:* vt_blk
+",
+            "",
+        );
+        let options = ModuleOptions::default();
+        let mut loader = loader(&index, &options);
+        let mut seen = HashSet::new();
+
+        let modaliases = [
+            "pci:v00001AF4d00001042sv00001AF4sd00001100bc01sc00i00",
+            "pci:v00008086d000029C0sv00001AF4sd00001100bc06sc00i00",
+            "pci:v00001AF4d00001041sv00001AF4sd00001100bc02sc00i00",
+            "acpi:LNRO0005:",
+        ];
+        let modules =
+            loader.modules_for_devices(modaliases.map(str::to_string).to_vec(), &mut seen);
+        assert_eq!(modules, ["vt_pci", "vt_blk"]);
+        loader.load("vt_pci").unwrap();
+
+        // Devices added by the bus driver are found in the next round
+        let modaliases = [
+            modaliases[0],
+            "virtio:d00000002v00001AF4",
+            "virtio:d00000001v00001AF4",
+            "virtio:d00000010v00001AF4",
+        ];
+        let modules =
+            loader.modules_for_devices(modaliases.map(str::to_string).to_vec(), &mut seen);
+        assert_eq!(modules, ["vt_blk", "vt_net"]);
+
+        let modules =
+            loader.modules_for_devices(modaliases.map(str::to_string).to_vec(), &mut seen);
+        assert!(modules.is_empty());
+    }
+}
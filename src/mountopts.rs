@@ -0,0 +1,271 @@
+//! Parsing of mount option strings like "ro,nodev,dax=always"
+//!
+//! Generic options are turned into MsFlags, the rest are checked against
+//! the options the filesystem type supports and passed on as mount data.
+
+use nix::mount::MsFlags;
+
+/// Generic mount options: (name, flag, whether the option sets or clears it)
+const FLAG_OPTIONS: &[(&str, MsFlags, bool)] = &[
+    ("ro", MsFlags::MS_RDONLY, true),
+    ("rw", MsFlags::MS_RDONLY, false),
+    ("nosuid", MsFlags::MS_NOSUID, true),
+    ("suid", MsFlags::MS_NOSUID, false),
+    ("nodev", MsFlags::MS_NODEV, true),
+    ("dev", MsFlags::MS_NODEV, false),
+    ("noexec", MsFlags::MS_NOEXEC, true),
+    ("exec", MsFlags::MS_NOEXEC, false),
+    ("sync", MsFlags::MS_SYNCHRONOUS, true),
+    ("async", MsFlags::MS_SYNCHRONOUS, false),
+    ("dirsync", MsFlags::MS_DIRSYNC, true),
+    ("noatime", MsFlags::MS_NOATIME, true),
+    ("atime", MsFlags::MS_NOATIME, false),
+    ("nodiratime", MsFlags::MS_NODIRATIME, true),
+    ("diratime", MsFlags::MS_NODIRATIME, false),
+    ("relatime", MsFlags::MS_RELATIME, true),
+    ("norelatime", MsFlags::MS_RELATIME, false),
+    ("strictatime", MsFlags::MS_STRICTATIME, true),
+    ("lazytime", MsFlags::MS_LAZYTIME, true),
+    ("nolazytime", MsFlags::MS_LAZYTIME, false),
+];
+
+/// The kind of value a filesystem specific option takes
+enum Value {
+    None,
+    Number,
+    /// Any non-empty value, like a user name
+    Text,
+    Choice(&'static [&'static str]),
+    /// Either no value, or one of the choices
+    OptionalChoice(&'static [&'static str]),
+}
+
+/// Filesystem specific options we know are valid, per filesystem type.
+/// Options for other filesystem types are passed on without validation.
+fn data_options(fstype: &str) -> Option<&'static [(&'static str, Value)]> {
+    match fstype {
+        // The kernel sets the other FUSE options itself for virtiofs, and
+        // rejects them as mount options
+        "virtiofs" => Some(&[("dax", Value::OptionalChoice(&["always", "never", "inode"]))]),
+        // The transport and protocol version options are set by us
+        "9p" => Some(&[
+            ("msize", Value::Number),
+            (
+                "cache",
+                Value::Choice(&["none", "loose", "fscache", "mmap", "readahead"]),
+            ),
+            ("loose", Value::None),
+            ("fscache", Value::None),
+            ("mmap", Value::None),
+            ("cachetag", Value::Text),
+            ("posixacl", Value::None),
+            // user, any, client or a uid
+            ("access", Value::Text),
+            ("uname", Value::Text),
+            ("aname", Value::Text),
+            ("dfltuid", Value::Number),
+            ("dfltgid", Value::Number),
+            ("afid", Value::Number),
+            ("debug", Value::Text),
+            ("locktimeout", Value::Number),
+            ("nodevmap", Value::None),
+            ("noxattr", Value::None),
+            ("directio", Value::None),
+            ("ignoreqv", Value::None),
+        ]),
+        _ => None,
+    }
+}
+
+fn validate_data_option(fstype: &str, name: &str, value: Option<&str>) -> Result<(), String> {
+    let Some(known) = data_options(fstype) else {
+        return Ok(());
+    };
+    let Some((_, kind)) = known.iter().find(|(n, _)| *n == name) else {
+        return Err(format!("unknown {} mount option '{}'", fstype, name));
+    };
+
+    let valid = match (kind, value) {
+        (Value::None, None) => true,
+        (Value::Number, Some(value)) => value.parse::<u64>().is_ok(),
+        (Value::Text, Some(value)) => !value.is_empty(),
+        (Value::Choice(choices), Some(value)) => choices.contains(&value),
+        (Value::OptionalChoice(_), None) => true,
+        (Value::OptionalChoice(choices), Some(value)) => choices.contains(&value),
+        _ => false,
+    };
+
+    if !valid {
+        let expected = match kind {
+            Value::None => "no value".to_string(),
+            Value::Number => "a number".to_string(),
+            Value::Text => "a value".to_string(),
+            Value::Choice(choices) | Value::OptionalChoice(choices) => choices.join("|"),
+        };
+        return Err(format!(
+            "invalid {} mount option '{}', expected {}",
+            fstype,
+            value.map_or(name.to_string(), |v| format!("{}={}", name, v)),
+            expected
+        ));
+    }
+
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct MountOptions {
+    pub flags: MsFlags,
+    data: Vec<String>,
+}
+
+impl MountOptions {
+    /// Default options, read-only or read-write
+    pub fn new(read_only: bool) -> Self {
+        MountOptions {
+            flags: if read_only {
+                MsFlags::MS_RDONLY
+            } else {
+                MsFlags::empty()
+            },
+            data: Vec::new(),
+        }
+    }
+
+    /// Parse a comma separated option string for the filesystem type,
+    /// applied on top of the defaults for read_only
+    pub fn parse(fstype: &str, options: &str, read_only: bool) -> Result<Self, String> {
+        let mut result = Self::new(read_only);
+
+        for option in options.split(',').filter(|o| !o.is_empty()) {
+            if let Some((_, flag, set)) = FLAG_OPTIONS.iter().find(|(n, _, _)| *n == option) {
+                result.flags.set(*flag, *set);
+                continue;
+            }
+
+            let (name, value) = match option.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (option, None),
+            };
+            validate_data_option(fstype, name, value)?;
+            result.data.push(option.to_string());
+        }
+
+        Ok(result)
+    }
+
+    /// The filesystem specific options, as passed to mount(2)
+    pub fn data(&self) -> Option<String> {
+        if self.data.is_empty() {
+            None
+        } else {
+            Some(self.data.join(","))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(fstype: &str, options: &str) -> String {
+        MountOptions::parse(fstype, options, false).unwrap_err()
+    }
+
+    #[test]
+    fn flags() {
+        let options = MountOptions::parse("virtiofs", "", true).unwrap();
+        assert_eq!(options.flags, MsFlags::MS_RDONLY);
+        assert_eq!(options.data(), None);
+
+        let options = MountOptions::parse("virtiofs", "rw,nosuid,nodev,noatime", true).unwrap();
+        assert_eq!(
+            options.flags,
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOATIME
+        );
+
+        // The last one wins
+        let options = MountOptions::parse("9p", "ro,nosuid,rw,suid,dev,,noexec", false).unwrap();
+        assert_eq!(options.flags, MsFlags::MS_NOEXEC);
+        let options = MountOptions::parse("9p", "rw,ro", false).unwrap();
+        assert_eq!(options.flags, MsFlags::MS_RDONLY);
+    }
+
+    #[test]
+    fn data() {
+        let options = MountOptions::parse("virtiofs", "dax,nodev,dax=inode", false).unwrap();
+        assert_eq!(options.flags, MsFlags::MS_NODEV);
+        assert_eq!(options.data().as_deref(), Some("dax,dax=inode"));
+
+        let options = MountOptions::parse(
+            "9p",
+            "msize=262144,cache=loose,access=client,posixacl",
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            options.data().as_deref(),
+            Some("msize=262144,cache=loose,access=client,posixacl")
+        );
+
+        // Other filesystems' options aren't checked
+        let options = MountOptions::parse("ext4", "noatime,errors=remount-ro", false).unwrap();
+        assert_eq!(options.flags, MsFlags::MS_NOATIME);
+        assert_eq!(options.data().as_deref(), Some("errors=remount-ro"));
+    }
+
+    #[test]
+    fn unknown_options() {
+        assert_eq!(
+            error("virtiofs", "allow_other"),
+            "unknown virtiofs mount option 'allow_other'"
+        );
+        assert_eq!(
+            error("virtiofs", "max_read=4096"),
+            "unknown virtiofs mount option 'max_read'"
+        );
+        assert_eq!(error("9p", "dax"), "unknown 9p mount option 'dax'");
+        // The transport and protocol version are always set by us
+        assert_eq!(error("9p", "trans=tcp"), "unknown 9p mount option 'trans'");
+        assert_eq!(
+            error("9p", "version=9p2000.u"),
+            "unknown 9p mount option 'version'"
+        );
+    }
+
+    #[test]
+    fn invalid_values() {
+        assert_eq!(
+            error("virtiofs", "dax=sometimes"),
+            "invalid virtiofs mount option 'dax=sometimes', expected always|never|inode"
+        );
+        assert_eq!(
+            error("9p", "cache=all"),
+            "invalid 9p mount option 'cache=all', expected none|loose|fscache|mmap|readahead"
+        );
+        assert_eq!(
+            error("9p", "cache"),
+            "invalid 9p mount option 'cache', expected none|loose|fscache|mmap|readahead"
+        );
+        assert_eq!(
+            error("9p", "msize=big"),
+            "invalid 9p mount option 'msize=big', expected a number"
+        );
+        assert_eq!(
+            error("9p", "msize=-1"),
+            "invalid 9p mount option 'msize=-1', expected a number"
+        );
+        assert_eq!(
+            error("9p", "msize"),
+            "invalid 9p mount option 'msize', expected a number"
+        );
+        assert_eq!(
+            error("9p", "posixacl=1"),
+            "invalid 9p mount option 'posixacl=1', expected no value"
+        );
+        assert_eq!(
+            error("9p", "uname="),
+            "invalid 9p mount option 'uname=', expected a value"
+        );
+    }
+}
@@ -0,0 +1,62 @@
+//! Setting up the console as the controlling terminal
+//!
+//! The kernel starts init with /dev/console as stdin, stdout and stderr, but
+//! /dev/console can't be a controlling terminal, so shells can't do job
+//! control and ^C doesn't reach them. Instead we open the real device behind
+//! it, like ttyS0 or hvc0, in a new session.
+
+use nix::unistd::setsid;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::fd::{AsRawFd, IntoRawFd};
+use std::path::{Path, PathBuf};
+
+/// Find the device node of the console. The last of the active consoles is
+/// the one /dev/console refers to, which is the last console= option.
+pub fn console_device() -> PathBuf {
+    let active = fs::read_to_string("/sys/class/tty/console/active").unwrap_or_default();
+    match active.split_whitespace().last() {
+        Some(name) => Path::new("/dev").join(name),
+        None => PathBuf::from("/dev/console"),
+    }
+}
+
+/// The TERM to use for a console: linux for virtual terminals and when we
+/// don't know, and vt220 for serial and hypervisor consoles
+pub fn term(device: &Path) -> &'static str {
+    let name = device
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    if name == "console" {
+        return "linux";
+    }
+    match name.strip_prefix("tty") {
+        Some(number) if number.chars().all(|c| c.is_ascii_digit()) => "linux",
+        _ => "vt220",
+    }
+}
+
+/// Start a new session with the console as controlling terminal, and as
+/// stdin, stdout and stderr
+pub fn set_controlling_terminal(device: &Path) -> io::Result<()> {
+    setsid()?;
+
+    let tty = OpenOptions::new().read(true).write(true).open(device)?;
+    if unsafe { libc::ioctl(tty.as_raw_fd(), libc::TIOCSCTTY, 1) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Without an initial console the tty may already have been opened as
+    // one of the standard fds, so don't close it in that case
+    let fd = tty.into_raw_fd();
+    for target in 0..=2 {
+        if fd != target && unsafe { libc::dup2(fd, target) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    if fd > 2 {
+        unsafe { libc::close(fd) };
+    }
+    Ok(())
+}